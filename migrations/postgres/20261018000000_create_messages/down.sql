@@ -0,0 +1,2 @@
+DROP TABLE encryption;
+DROP TABLE messages;
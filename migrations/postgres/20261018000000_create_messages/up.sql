@@ -0,0 +1,18 @@
+-- IF NOT EXISTS adopts databases created before migrations were embedded
+CREATE TABLE IF NOT EXISTS messages (
+    id BIGSERIAL PRIMARY KEY,
+    host_public_key BYTEA NOT NULL,
+    peer_public_key BYTEA NOT NULL,
+    direction INTEGER NOT NULL,
+    timestamp BIGINT NOT NULL,
+    data BYTEA NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS messages_pair
+    ON messages (host_public_key, peer_public_key, id);
+
+CREATE TABLE IF NOT EXISTS encryption (
+    id INTEGER PRIMARY KEY NOT NULL,
+    salt BYTEA NOT NULL,
+    key BYTEA NOT NULL
+);
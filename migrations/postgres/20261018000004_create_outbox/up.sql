@@ -0,0 +1,10 @@
+-- Data waiting for its peer to connect and acknowledge it, sent in ID order
+CREATE TABLE outbox (
+    id BIGSERIAL PRIMARY KEY,
+    host_public_key BYTEA NOT NULL,
+    peer_public_key BYTEA NOT NULL,
+    token BYTEA NOT NULL,
+    message_id BYTEA NOT NULL UNIQUE,
+    sent_at BIGINT NOT NULL,
+    data BYTEA NOT NULL
+);
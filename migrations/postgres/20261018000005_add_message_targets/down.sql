@@ -0,0 +1,2 @@
+DROP INDEX messages_target_id;
+ALTER TABLE messages DROP COLUMN target_id;
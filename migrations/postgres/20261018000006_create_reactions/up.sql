@@ -0,0 +1,9 @@
+-- The emojis each side reacted to a message with, encrypted as one set
+CREATE TABLE reactions (
+    host_public_key BYTEA NOT NULL,
+    peer_public_key BYTEA NOT NULL,
+    target_id BYTEA NOT NULL,
+    direction INTEGER NOT NULL,
+    emojis BYTEA NOT NULL,
+    PRIMARY KEY (host_public_key, peer_public_key, target_id, direction)
+);
@@ -0,0 +1,18 @@
+-- IF NOT EXISTS adopts databases created before migrations were embedded
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    host_public_key BLOB NOT NULL,
+    peer_public_key BLOB NOT NULL,
+    direction INTEGER NOT NULL,
+    timestamp BIGINT NOT NULL,
+    data BLOB NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS messages_pair
+    ON messages (host_public_key, peer_public_key, id);
+
+CREATE TABLE IF NOT EXISTS encryption (
+    id INTEGER PRIMARY KEY NOT NULL,
+    salt BLOB NOT NULL,
+    key BLOB NOT NULL
+);
@@ -0,0 +1,8 @@
+CREATE TABLE contacts (
+    host_public_key BLOB NOT NULL,
+    peer_public_key BLOB NOT NULL,
+    metadata BLOB NOT NULL,
+    first_seen BIGINT NOT NULL,
+    last_seen BIGINT,
+    PRIMARY KEY (host_public_key, peer_public_key)
+);
@@ -0,0 +1,2 @@
+-- Bodies are decoded and indexed by the application, rows share the ID of their message
+CREATE VIRTUAL TABLE messages_fts USING fts5(body);
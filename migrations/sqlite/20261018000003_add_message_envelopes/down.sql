@@ -0,0 +1,3 @@
+DROP INDEX messages_message_id;
+ALTER TABLE messages DROP COLUMN sent_at;
+ALTER TABLE messages DROP COLUMN message_id;
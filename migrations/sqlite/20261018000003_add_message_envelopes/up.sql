@@ -0,0 +1,6 @@
+ALTER TABLE messages ADD COLUMN message_id BLOB;
+ALTER TABLE messages ADD COLUMN sent_at BIGINT;
+
+-- Envelopes can arrive again when their ack was lost
+CREATE UNIQUE INDEX messages_message_id
+    ON messages (host_public_key, peer_public_key, direction, message_id);
@@ -0,0 +1,10 @@
+-- Data waiting for its peer to connect and acknowledge it, sent in ID order
+CREATE TABLE outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    host_public_key BLOB NOT NULL,
+    peer_public_key BLOB NOT NULL,
+    token BLOB NOT NULL,
+    message_id BLOB NOT NULL UNIQUE,
+    sent_at BIGINT NOT NULL,
+    data BLOB NOT NULL
+);
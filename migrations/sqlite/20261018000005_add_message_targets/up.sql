@@ -0,0 +1,5 @@
+-- The message an edit or deletion refers to, so they can be found without decrypting
+ALTER TABLE messages ADD COLUMN target_id BLOB;
+
+CREATE INDEX messages_target_id
+    ON messages (host_public_key, peer_public_key, target_id);
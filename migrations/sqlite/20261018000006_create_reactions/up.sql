@@ -0,0 +1,9 @@
+-- The emojis each side reacted to a message with, encrypted as one set
+CREATE TABLE reactions (
+    host_public_key BLOB NOT NULL,
+    peer_public_key BLOB NOT NULL,
+    target_id BLOB NOT NULL,
+    direction INTEGER NOT NULL,
+    emojis BLOB NOT NULL,
+    PRIMARY KEY (host_public_key, peer_public_key, target_id, direction)
+);
@@ -0,0 +1,8 @@
+-- Post-quantum identity keys of peers, pinned on first contact
+CREATE TABLE identity_keys (
+    host_public_key BLOB NOT NULL,
+    peer_public_key BLOB NOT NULL,
+    kyber_public_key BLOB NOT NULL,
+    pinned_at BIGINT NOT NULL,
+    PRIMARY KEY (host_public_key, peer_public_key)
+);
@@ -0,0 +1 @@
+DROP TRIGGER messages_fts_delete;
@@ -0,0 +1,4 @@
+-- Index entries go away with their message instead of rebuilding the index after deletes
+CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+    DELETE FROM messages_fts WHERE rowid = old.id;
+END;
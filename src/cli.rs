@@ -0,0 +1,133 @@
+use std::{env, path::PathBuf};
+
+use crate::{
+    client::model::PeerHostPair,
+    error::{BlackedoutError, Result},
+    storage::{archive::Archive, Storage},
+    types::PublicKey,
+};
+
+pub const USAGE: &str = "\
+Usage:
+    blackedoutchat
+    blackedoutchat export <archive> [--host <onion> --peer <onion>] [--encrypt]
+    blackedoutchat import <archive>
+
+Archive passphrases are read from BLACKEDOUT_ARCHIVE_PASSPHRASE or prompted for";
+
+/// Subcommands that work on the storage and exit without starting Tor
+pub enum Command {
+    /// Exports one conversation, or all of them, into a new archive directory
+    Export {
+        path: PathBuf,
+        pair: Option<PeerHostPair>,
+        encrypt: bool,
+    },
+    /// Merges an archive into every storage
+    Import { path: PathBuf },
+}
+
+impl Command {
+    /// Returns `None` when no subcommand is given and the chat should run
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Self>> {
+        let bad = |x: &str| BlackedoutError::BadArguments(x.to_string());
+
+        let command = match args.next() {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+
+        let path = args
+            .next()
+            .ok_or_else(|| bad("missing archive path"))?
+            .into();
+
+        match command.as_str() {
+            "export" => {
+                let (mut host, mut peer, mut encrypt) = (None, None, false);
+
+                while let Some(arg) = args.next() {
+                    let mut address = || -> Result<PublicKey> {
+                        PublicKey::from_onion_address(
+                            &args.next().ok_or_else(|| bad("missing onion address"))?,
+                        )
+                    };
+
+                    match arg.as_str() {
+                        "--host" => host = Some(address()?),
+                        "--peer" => peer = Some(address()?),
+                        "--encrypt" => encrypt = true,
+                        x => return Err(bad(x)),
+                    }
+                }
+
+                let pair = match (host, peer) {
+                    (Some(host_public_key), Some(peer_public_key)) => Some(PeerHostPair {
+                        peer_public_key,
+                        host_public_key,
+                    }),
+                    (None, None) => None,
+                    _ => return Err(bad("--host and --peer go together")),
+                };
+
+                Ok(Some(Command::Export {
+                    path,
+                    pair,
+                    encrypt,
+                }))
+            }
+            "import" => match args.next() {
+                Some(x) => Err(bad(&x)),
+                None => Ok(Some(Command::Import { path })),
+            },
+            x => Err(bad(x)),
+        }
+    }
+
+    pub fn run(self, storage: &Storage) -> Result<()> {
+        match self {
+            Command::Export {
+                path,
+                pair,
+                encrypt,
+            } => {
+                let passphrase = match encrypt {
+                    true => Some(read_passphrase()?),
+                    false => None,
+                };
+
+                let archive = storage.export(pair.as_ref())?;
+                archive.write(&path, passphrase.as_deref())?;
+
+                println!(
+                    "Exported {} contacts and {} messages",
+                    archive.contacts.len(),
+                    archive.messages.len()
+                );
+            }
+            Command::Import { path } => {
+                let (contacts, messages) =
+                    storage.import(&Archive::read(&path, read_passphrase)?)?;
+
+                println!(
+                    "Imported {} new contacts and {} new messages",
+                    contacts, messages
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn read_passphrase() -> Result<String> {
+    let passphrase = match env::var("BLACKEDOUT_ARCHIVE_PASSPHRASE") {
+        Ok(n) => n,
+        Err(_) => rpassword::prompt_password("Archive passphrase: ")?,
+    };
+
+    match passphrase.is_empty() {
+        true => Err(BlackedoutError::MissingPassphrase),
+        false => Ok(passphrase),
+    }
+}
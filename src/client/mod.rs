@@ -15,27 +15,39 @@ use axum::{
 use futures::{
     future::{try_join_all, TryFutureExt},
     stream::{SplitSink, StreamExt},
-    FutureExt, SinkExt,
+    SinkExt,
 };
-use tokio::sync::{
-    mpsc::{channel, Sender},
-    Mutex,
+use tokio::{
+    sync::{
+        mpsc::{channel, Sender},
+        Mutex,
+    },
+    task::spawn_blocking,
 };
 use tower_http::{cors::CorsLayer, services::ServeDir};
 
 use crate::{
-    config::{Client, Config},
+    config::{confine, Client, Config},
+    connections::{
+        model::{Data, Envelope, Transfer},
+        transfer::{self, Outgoing},
+        ToPeer,
+    },
     error::{BlackedoutError, Result},
     state::State,
-    storage::Storage,
+    storage::{
+        model::{Direction, OutboxEntry},
+        Storage,
+    },
     types::PublicKey,
 };
 
-use self::model::{ClientPacket, Initialize, PeerHostPair};
+use self::model::{ClientPacket, History, Initialize, PeerHostPair, RoundTrip, SearchResults};
 
 type OutgoingTx = Sender<(PublicKey, PublicKey, Sender<Result<()>>)>;
 type FutureBoxed = Pin<Box<dyn Future<Output = Result<()>>>>;
 type ConnectedClients = HashMap<[u8; 32], SplitSink<WebSocket, Message>>;
+type PeerTx = Sender<ToPeer>;
 
 pub async fn start_clients(
     config: &Config,
@@ -82,6 +94,7 @@ pub async fn start_clients(
                                 .route("/ws", get(ws_handler))
                                 .layer(Extension(connected_clients))
                                 .layer(Extension(state.clone()))
+                                .layer(Extension(storage.clone()))
                                 .layer(Extension(outgoing_tx.clone()))
                                 .layer(CorsLayer::permissive())
                                 .into_make_service(),
@@ -119,30 +132,66 @@ async fn connect_handler(
 async fn ws_handler(
     ws: WebSocketUpgrade,
     state: Extension<Arc<Mutex<State>>>,
+    storage: Extension<Arc<Storage>>,
     outgoing_tx: Extension<OutgoingTx>,
     connected_clients: Extension<Arc<Mutex<ConnectedClients>>>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| ws_socket_handler(socket, state, outgoing_tx, connected_clients))
+    ws.on_upgrade(|socket| {
+        ws_socket_handler(socket, state, storage, outgoing_tx, connected_clients)
+    })
 }
 
 async fn ws_socket_handler(
     socket: WebSocket,
     Extension(state): Extension<Arc<Mutex<State>>>,
+    Extension(storage): Extension<Arc<Storage>>,
     Extension(outgoing_txt): Extension<OutgoingTx>,
     Extension(connected_clients): Extension<Arc<Mutex<ConnectedClients>>>,
 ) {
     let (mut tx, mut rx) = socket.split();
 
-    let connected_peers = state
-        .lock()
+    let (connected_peers, round_trips) = {
+        let state = state.lock().await;
+
+        let connected_peers = state
+            .addresses
+            .iter()
+            .map(|(a, b)| (*a, b.connected_peers.keys().copied().collect::<Vec<_>>()))
+            .collect();
+
+        let round_trips = state
+            .addresses
+            .iter()
+            .flat_map(|(host, address)| {
+                address.round_trips.iter().map(|(peer, rtt)| RoundTrip {
+                    pair: PeerHostPair {
+                        peer_public_key: *peer,
+                        host_public_key: *host,
+                    },
+                    millis: rtt.as_millis() as u64,
+                })
+            })
+            .collect();
+
+        (connected_peers, round_trips)
+    };
+
+    let storage0 = storage.clone();
+    let outbox = spawn_blocking(move || storage0.outbox(None))
         .await
-        .addresses
-        .iter()
-        .map(|(a, b)| (*a, b.connected_peers.keys().map(|x| *x).collect::<Vec<_>>()))
-        .collect();
+        .unwrap()
+        .unwrap_or_else(|e| {
+            println!("Error reading outbox: {:?}", e);
+            Vec::new()
+        });
 
     tx.send(Message::Text(
-        serde_json::to_string(&ClientPacket::Initialize(Initialize { connected_peers })).unwrap(),
+        serde_json::to_string(&ClientPacket::Initialize(Initialize {
+            connected_peers,
+            outbox,
+            round_trips,
+        }))
+        .unwrap(),
     ))
     .await
     .unwrap();
@@ -164,22 +213,236 @@ async fn ws_socket_handler(
         };
 
         if let Err(e) = match n {
+            ClientPacket::SendData {
+                data: Data::Read(_) | Data::ReadReceipts { .. },
+                ..
+            } => Err(BlackedoutError::WrongPacketType(
+                "Read receipts are sent with mark_read".to_string(),
+            )),
+            ClientPacket::SendData { token, pair, data } if data.ephemeral() => {
+                async {
+                    peer_tx(&state, &pair)
+                        .await?
+                        .ok_or(BlackedoutError::PeerPublicKeyDoesNotExist)?
+                        .send(ToPeer::Data(Some(token), Envelope::new(data)))
+                        .await
+                        .map_err(|_| BlackedoutError::Unexpected)
+                }
+                .await
+            }
             ClientPacket::SendData { token, pair, data } => {
-                state
-                    .lock()
-                    .then(|state| async move {
-                        state
-                            .addresses
-                            .get(&pair.host_public_key)
-                            .ok_or(BlackedoutError::HostPublicKeyDoesNotExist)?
-                            .connected_peers
-                            .get(&pair.peer_public_key)
-                            .ok_or(BlackedoutError::PeerPublicKeyDoesNotExist)?
-                            .send((token, data))
-                            .map_err(|_| BlackedoutError::Unexpected)
+                let storage0 = storage.clone();
+
+                async {
+                    peer_tx(&state, &pair).await?;
+
+                    let entry = OutboxEntry {
+                        token,
+                        pair,
+                        envelope: Envelope::new(data),
+                    };
+                    let entry0 = entry.clone();
+
+                    spawn_blocking(move || {
+                        storage0.check_target(&entry0.pair, &entry0.envelope.data)?;
+                        storage0.enqueue(&entry0)
+                    })
+                    .await
+                    .map_err(|_| BlackedoutError::Unexpected)??;
+
+                    storage
+                        .broadcast(ClientPacket::SendDataPending(entry.clone()))
+                        .await;
+
+                    // Looked up again after queueing, see `connection_loop`. Without a
+                    // connection the dialer takes over.
+                    match peer_tx(&state, &entry.pair).await? {
+                        Some(peer) => peer
+                            .send(ToPeer::Data(Some(token), entry.envelope))
                             .await
+                            .map_err(|_| BlackedoutError::Unexpected),
+                        None => Ok(()),
+                    }
+                }
+                .await
+            }
+            ClientPacket::FetchHistory(query) => {
+                let storage = storage.clone();
+
+                async {
+                    let history = spawn_blocking(move || {
+                        storage.fetch_history(&query).map(|messages| History {
+                            pair: query.pair,
+                            messages,
+                        })
                     })
                     .await
+                    .map_err(|_| BlackedoutError::Unexpected)??;
+
+                    connected_clients
+                        .lock()
+                        .await
+                        .get_mut(&id)
+                        .unwrap()
+                        .send(Message::Text(
+                            serde_json::to_string(&ClientPacket::History(history)).unwrap(),
+                        ))
+                        .await
+                        .map_err(Into::into)
+                }
+                .await
+            }
+            ClientPacket::MarkRead { pair, message_ids } => {
+                let storage = storage.clone();
+                let pair0 = pair.clone();
+
+                async {
+                    // Nothing is sent when receipts are off for this peer
+                    if !spawn_blocking(move || storage.read_receipts(&pair0))
+                        .await
+                        .map_err(|_| BlackedoutError::Unexpected)??
+                    {
+                        return Ok(());
+                    }
+
+                    peer_tx(&state, &pair)
+                        .await?
+                        .ok_or(BlackedoutError::PeerPublicKeyDoesNotExist)?
+                        .send(ToPeer::Data(None, Envelope::new(Data::Read(message_ids))))
+                        .await
+                        .map_err(|_| BlackedoutError::Unexpected)
+                }
+                .await
+            }
+            ClientPacket::SendFile { pair, path, mime } => {
+                async {
+                    peer_tx(&state, &pair).await?;
+
+                    let path = confine(&state.lock().await.transfers.shared, &path);
+                    let path0 = path.clone();
+                    let offer = spawn_blocking(move || transfer::offer(&path0, mime))
+                        .await
+                        .map_err(|_| BlackedoutError::Unexpected)??;
+
+                    state.lock().await.transfers.outgoing.insert(
+                        offer.transfer_id,
+                        Outgoing {
+                            pair: pair.clone(),
+                            path,
+                            offer: offer.clone(),
+                        },
+                    );
+
+                    storage
+                        .broadcast(ClientPacket::FileOffered {
+                            pair: pair.clone(),
+                            direction: Direction::Outgoing,
+                            offer: offer.clone(),
+                        })
+                        .await;
+
+                    // Otherwise offered once the peer connects
+                    match peer_tx(&state, &pair).await? {
+                        Some(peer) => peer
+                            .send(ToPeer::Transfer(Transfer::Offer(offer)))
+                            .await
+                            .map_err(|_| BlackedoutError::Unexpected),
+                        None => Ok(()),
+                    }
+                }
+                .await
+            }
+            ClientPacket::AcceptFile { pair, transfer_id } => {
+                async {
+                    match transfer::accept(&state, &pair, &transfer_id).await? {
+                        Some((peer, accept)) => peer
+                            .send(ToPeer::Transfer(accept))
+                            .await
+                            .map_err(|_| BlackedoutError::Unexpected),
+                        None => Ok(()),
+                    }
+                }
+                .await
+            }
+            ClientPacket::RejectFile { pair, transfer_id } => {
+                async {
+                    match transfer::reject(&state, &pair, &transfer_id).await? {
+                        Some((peer, reject)) => peer
+                            .send(ToPeer::Transfer(reject))
+                            .await
+                            .map_err(|_| BlackedoutError::Unexpected),
+                        None => Ok(()),
+                    }
+                }
+                .await
+            }
+            ClientPacket::Search(search) => {
+                let storage = storage.clone();
+
+                async {
+                    let results = spawn_blocking(move || {
+                        storage.search(&search).map(|results| SearchResults {
+                            query: search.query,
+                            results,
+                        })
+                    })
+                    .await
+                    .map_err(|_| BlackedoutError::Unexpected)??;
+
+                    connected_clients
+                        .lock()
+                        .await
+                        .get_mut(&id)
+                        .unwrap()
+                        .send(Message::Text(
+                            serde_json::to_string(&ClientPacket::SearchResults(results)).unwrap(),
+                        ))
+                        .await
+                        .map_err(Into::into)
+                }
+                .await
+            }
+            ClientPacket::ListContacts => {
+                let storage = storage.clone();
+
+                async {
+                    let contacts = spawn_blocking(move || storage.contacts())
+                        .await
+                        .map_err(|_| BlackedoutError::Unexpected)??;
+
+                    connected_clients
+                        .lock()
+                        .await
+                        .get_mut(&id)
+                        .unwrap()
+                        .send(Message::Text(
+                            serde_json::to_string(&ClientPacket::Contacts(contacts)).unwrap(),
+                        ))
+                        .await
+                        .map_err(Into::into)
+                }
+                .await
+            }
+            // The peer is told right away if read receipts were turned on or off for it
+            ClientPacket::AddContact(contact) => {
+                let pair = contact.pair.clone();
+                let enabled = storage.read_receipts_for(Some(&contact.metadata));
+
+                storage.send_packet(ClientPacket::AddContact(contact)).await;
+                send_read_receipts(&state, &pair, enabled).await
+            }
+            ClientPacket::RemoveContact(pair) => {
+                storage
+                    .send_packet(ClientPacket::RemoveContact(pair.clone()))
+                    .await;
+                send_read_receipts(&state, &pair, storage.read_receipts_for(None)).await
+            }
+            // Passed through storage so every client and every backend sees the change
+            packet @ (ClientPacket::DeleteHistory(_)
+            | ClientPacket::RenameContact { .. }
+            | ClientPacket::UnpinIdentityKey(_)) => {
+                storage.send_packet(packet).await;
+                Ok(())
             }
             _ => Err(BlackedoutError::WrongPacketType(
                 "Unexpected packet".to_string(),
@@ -199,3 +462,34 @@ async fn ws_socket_handler(
     // Disconnected
     connected_clients.lock().await.remove(&id);
 }
+
+/// Tells a connected peer whether it gets read receipts
+async fn send_read_receipts(
+    state: &Mutex<State>,
+    pair: &PeerHostPair,
+    enabled: bool,
+) -> Result<()> {
+    match peer_tx(state, pair).await? {
+        Some(peer) => peer
+            .send(ToPeer::Data(
+                None,
+                Envelope::new(Data::ReadReceipts { enabled }),
+            ))
+            .await
+            .map_err(|_| BlackedoutError::Unexpected),
+        None => Ok(()),
+    }
+}
+
+/// The channel to a peer if it is connected. Fails for host addresses that don't exist.
+async fn peer_tx(state: &Mutex<State>, pair: &PeerHostPair) -> Result<Option<PeerTx>> {
+    Ok(state
+        .lock()
+        .await
+        .addresses
+        .get(&pair.host_public_key)
+        .ok_or(BlackedoutError::HostPublicKeyDoesNotExist)?
+        .connected_peers
+        .get(&pair.peer_public_key)
+        .cloned())
+}
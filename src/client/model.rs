@@ -1,9 +1,15 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
 use serde_with::{base64::Base64, serde_as};
 
-use crate::{connections::model::Data, types::PublicKey};
+use crate::{
+    connections::model::{Data, Envelope, FileOffer},
+    storage::model::{
+        Contact, ContactMetadata, Direction, Message, OutboxEntry, Reaction, SearchResult,
+    },
+    types::{MessageId, PublicKey},
+};
 
 #[serde_as]
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -11,12 +17,25 @@ use crate::{connections::model::Data, types::PublicKey};
 pub enum ClientPacket {
     Connect(PeerHostPair),
     ConnectionEstablished(PeerHostPair),
+    /// Sent whenever a connected peer answers a ping
+    RoundTrip(RoundTrip),
     Initialize(Initialize),
-    Disconnected(PeerHostPair),
+    /// A peer presented another identity key than the one pinned for it, so its connection
+    /// was refused. Either someone is impersonating it or it reinstalled, which only it can
+    /// tell out of band. `UnpinIdentityKey` accepts the next key it presents.
+    IdentityKeyChanged(PeerHostPair),
+    UnpinIdentityKey(PeerHostPair),
+    Disconnected {
+        #[serde(flatten)]
+        pair: PeerHostPair,
+        /// Why the peer was disconnected if it was its fault, such as `rate_limited`
+        reason: Option<String>,
+    },
     DataReceived {
         #[serde(flatten)]
         pair: PeerHostPair,
-        data: Data,
+        #[serde(flatten)]
+        envelope: Envelope,
     },
     SendData {
         #[serde_as(as = "Base64")]
@@ -25,13 +44,113 @@ pub enum ClientPacket {
         pair: PeerHostPair,
         data: Data,
     },
+    /// The data is in the outbox and shown as pending until its confirmation. It stays queued
+    /// while the peer is offline and is sent again on every connection until acknowledged.
+    SendDataPending(OutboxEntry),
+    /// Sent once the peer acknowledged delivery
     SendDataConfirmation {
         #[serde_as(as = "Base64")]
         token: [u8; 12],
+        #[serde(flatten)]
+        pair: PeerHostPair,
+        #[serde(flatten)]
+        envelope: Envelope,
+    },
+    /// The peer rejected data, like an edit of a message that isn't ours, and it was dropped
+    /// from the outbox. Also sent for data that isn't queued in the outbox and couldn't be sent
+    /// or confirmed before the connection to the peer ended, or that the peer doesn't support.
+    SendDataFailed {
+        #[serde_as(as = "Base64")]
+        token: [u8; 12],
+        #[serde(flatten)]
+        pair: PeerHostPair,
+        message_id: MessageId,
+    },
+    FetchHistory(FetchHistory),
+    History(History),
+    DeleteHistory(DeleteHistory),
+    ListContacts,
+    Contacts(Vec<Contact>),
+    AddContact(AddContact),
+    RenameContact {
+        #[serde(flatten)]
+        pair: PeerHostPair,
+        nickname: String,
+    },
+    RemoveContact(PeerHostPair),
+    Search(Search),
+    SearchResults(SearchResults),
+    /// Sent by a client once it displayed incoming messages
+    MarkRead {
+        #[serde(flatten)]
+        pair: PeerHostPair,
+        message_ids: Vec<MessageId>,
+    },
+    /// Every reaction to a message after one was added or removed, by either side
+    Reactions {
+        #[serde(flatten)]
+        pair: PeerHostPair,
+        target_id: MessageId,
+        reactions: Vec<Reaction>,
+    },
+    /// The peer displayed messages sent to it
+    ReadReceipt {
+        #[serde(flatten)]
+        pair: PeerHostPair,
+        message_ids: Vec<MessageId>,
+    },
+    /// Offers a file in the shared directory to a peer, `path` being relative to it. It is
+    /// offered again on every connection until the peer completes or rejects it.
+    SendFile {
+        #[serde(flatten)]
+        pair: PeerHostPair,
+        path: PathBuf,
+        mime: Option<String>,
+    },
+    FileOffered {
+        #[serde(flatten)]
+        pair: PeerHostPair,
+        direction: Direction,
+        #[serde(flatten)]
+        offer: FileOffer,
+    },
+    AcceptFile {
+        #[serde(flatten)]
+        pair: PeerHostPair,
+        transfer_id: MessageId,
+    },
+    RejectFile {
+        #[serde(flatten)]
+        pair: PeerHostPair,
+        transfer_id: MessageId,
+    },
+    FileRejected {
+        #[serde(flatten)]
+        pair: PeerHostPair,
+        transfer_id: MessageId,
+        direction: Direction,
+    },
+    /// `offset` bytes were sent or received so far
+    FileProgress {
+        #[serde(flatten)]
+        pair: PeerHostPair,
+        transfer_id: MessageId,
+        direction: Direction,
+        offset: u64,
+        size: u64,
+    },
+    /// Received files are only kept if `verified`, in `path`
+    FileCompleted {
+        #[serde(flatten)]
+        pair: PeerHostPair,
+        transfer_id: MessageId,
+        direction: Direction,
+        verified: bool,
+        path: Option<PathBuf>,
     },
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
 pub struct PeerHostPair {
     pub peer_public_key: PublicKey,
     pub host_public_key: PublicKey,
@@ -40,4 +159,75 @@ pub struct PeerHostPair {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Initialize {
     pub connected_peers: HashMap<PublicKey, Vec<PublicKey>>,
+    /// Everything still pending, oldest first
+    pub outbox: Vec<OutboxEntry>,
+    pub round_trips: Vec<RoundTrip>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RoundTrip {
+    #[serde(flatten)]
+    pub pair: PeerHostPair,
+    pub millis: u64,
+}
+
+/// Requests a page of messages from a conversation ordered by message ID.
+/// Without `before` or `after` the latest page is returned.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FetchHistory {
+    #[serde(flatten)]
+    pub pair: PeerHostPair,
+    pub before: Option<i64>,
+    pub after: Option<i64>,
+    #[serde(default = "default_history_limit")]
+    pub limit: i64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct History {
+    #[serde(flatten)]
+    pub pair: PeerHostPair,
+    pub messages: Vec<Message>,
+}
+
+/// Deletes a conversation, or only the messages stored at or before `until`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeleteHistory {
+    #[serde(flatten)]
+    pub pair: PeerHostPair,
+    pub until: Option<i64>,
+}
+
+/// Adds a contact, or replaces the metadata of an existing one
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AddContact {
+    #[serde(flatten)]
+    pub pair: PeerHostPair,
+    #[serde(flatten)]
+    pub metadata: ContactMetadata,
+}
+
+/// Searches message bodies for every word of `query`, newest first. The other fields narrow
+/// the search down to a host identity, a peer or a time range.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Search {
+    pub query: String,
+    pub host_public_key: Option<PublicKey>,
+    pub peer_public_key: Option<PublicKey>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    #[serde(default = "default_history_limit")]
+    pub limit: i64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SearchResults {
+    pub query: String,
+    pub results: Vec<SearchResult>,
+}
+
+pub const MAX_HISTORY_LIMIT: i64 = 200;
+
+fn default_history_limit() -> i64 {
+    50
 }
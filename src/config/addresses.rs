@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use super::Retention;
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Addresses {
     #[serde(rename = "address")]
@@ -10,6 +12,9 @@ pub struct Addresses {
 pub struct Address {
     pub name: String,
     pub color: [u8; 3],
+    /// Overrides the global retention for conversations of this address
+    #[serde(default)]
+    pub retention: Option<Retention>,
 }
 
 impl super::ConfigTrait for Addresses {
@@ -24,6 +29,7 @@ impl Default for Addresses {
             addresses: vec![Address {
                 name: "default".to_string(),
                 color: [255, 255, 255],
+                retention: None,
             }],
         }
     }
@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Connections {
+    /// Seconds between pings to every connected peer
+    #[serde(default = "default_ping_interval")]
+    pub ping_interval: u64,
+    /// Unanswered pings in a row after which a connection is considered dead
+    #[serde(default = "default_max_missed_pings")]
+    pub max_missed_pings: u32,
+    /// Largest frame in bytes a peer may send. Has to fit a file chunk.
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+    #[serde(default = "default_messages_per_second")]
+    pub messages_per_second: u32,
+    #[serde(default = "default_bytes_per_second")]
+    pub bytes_per_second: u64,
+    /// Seconds a peer may stay more than a second worth over the limits before it is
+    /// disconnected
+    #[serde(default = "default_max_throttle")]
+    pub max_throttle: u64,
+    /// Packets sent with one key before it is replaced, though keys last at least 10 seconds
+    #[serde(default = "default_rekey_messages")]
+    pub rekey_messages: u64,
+    /// Minutes after which a key is replaced even if few packets were sent with it
+    #[serde(default = "default_rekey_minutes")]
+    pub rekey_minutes: u64,
+}
+
+impl super::ConfigTrait for Connections {
+    fn name() -> &'static str {
+        "connections"
+    }
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Connections {
+            ping_interval: default_ping_interval(),
+            max_missed_pings: default_max_missed_pings(),
+            max_frame_size: default_max_frame_size(),
+            messages_per_second: default_messages_per_second(),
+            bytes_per_second: default_bytes_per_second(),
+            max_throttle: default_max_throttle(),
+            rekey_messages: default_rekey_messages(),
+            rekey_minutes: default_rekey_minutes(),
+        }
+    }
+}
+
+fn default_ping_interval() -> u64 {
+    30
+}
+
+fn default_max_missed_pings() -> u32 {
+    3
+}
+
+fn default_max_frame_size() -> usize {
+    1024 * 1024
+}
+
+fn default_messages_per_second() -> u32 {
+    50
+}
+
+fn default_bytes_per_second() -> u64 {
+    2 * 1024 * 1024
+}
+
+fn default_max_throttle() -> u64 {
+    10
+}
+
+fn default_rekey_messages() -> u64 {
+    10000
+}
+
+fn default_rekey_minutes() -> u64 {
+    10
+}
@@ -0,0 +1,82 @@
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Files {
+    /// Where received files are kept, relative to `data/`
+    #[serde(default = "default_directory")]
+    pub directory: PathBuf,
+    /// The only place clients may send files from, relative to `data/`
+    #[serde(default = "default_shared")]
+    pub shared: PathBuf,
+}
+
+impl Files {
+    /// The download directory, inside `data/`
+    pub fn directory(&self) -> PathBuf {
+        confine(Path::new("data"), &self.directory)
+    }
+
+    /// The directory files are sent from, inside `data/`
+    pub fn shared(&self) -> PathBuf {
+        confine(Path::new("data"), &self.shared)
+    }
+}
+
+impl super::ConfigTrait for Files {
+    fn name() -> &'static str {
+        "files"
+    }
+}
+
+impl Default for Files {
+    fn default() -> Self {
+        Files {
+            directory: default_directory(),
+            shared: default_shared(),
+        }
+    }
+}
+
+fn default_directory() -> PathBuf {
+    PathBuf::from("downloads")
+}
+
+fn default_shared() -> PathBuf {
+    PathBuf::from("shared")
+}
+
+/// `path` inside `directory`. Only plain components are kept so it can't leave it.
+pub fn confine(directory: &Path, path: &Path) -> PathBuf {
+    directory.join(
+        path.components()
+            .filter(|x| matches!(x, Component::Normal(_)))
+            .collect::<PathBuf>(),
+    )
+}
+
+#[test]
+fn download_directory() {
+    let files = |x: &str| Files {
+        directory: PathBuf::from(x),
+        shared: PathBuf::from(x),
+    };
+
+    assert_eq!(
+        files("downloads").directory(),
+        PathBuf::from("data/downloads")
+    );
+    assert_eq!(
+        files("/etc/../x/./y").directory(),
+        PathBuf::from("data/etc/x/y")
+    );
+    assert_eq!(
+        files("/etc/../x/./y").shared(),
+        PathBuf::from("data/etc/x/y")
+    );
+    assert_eq!(
+        confine(Path::new("data/shared"), Path::new("../../.ssh/id_ed25519")),
+        PathBuf::from("data/shared/.ssh/id_ed25519")
+    );
+}
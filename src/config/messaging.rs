@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Messaging {
+    /// Send read receipts and show the ones peers send. Contacts can override this.
+    #[serde(default = "default_true")]
+    pub read_receipts: bool,
+}
+
+impl super::ConfigTrait for Messaging {
+    fn name() -> &'static str {
+        "messaging"
+    }
+}
+
+impl Default for Messaging {
+    fn default() -> Self {
+        Messaging {
+            read_receipts: true,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
@@ -1,5 +1,8 @@
 pub mod addresses;
 pub mod clients;
+pub mod connections;
+pub mod files;
+pub mod messaging;
 pub mod storage;
 
 use serde::de::DeserializeOwned;
@@ -12,11 +15,17 @@ use std::path::PathBuf;
 
 pub use self::addresses::*;
 pub use self::clients::*;
+pub use self::connections::*;
+pub use self::files::*;
+pub use self::messaging::*;
 pub use self::storage::*;
 
 pub struct Config {
     pub addresses: Addresses,
     pub clients: Clients,
+    pub connections: Connections,
+    pub files: Files,
+    pub messaging: Messaging,
     pub storage: Storages,
 }
 
@@ -25,6 +34,9 @@ impl Config {
         Config {
             addresses: Addresses::load(),
             clients: Clients::load(),
+            connections: Connections::load(),
+            files: Files::load(),
+            messaging: Messaging::load(),
             storage: Storages::load(),
         }
     }
@@ -85,6 +97,21 @@ fn test_serialize_clients() {
     Clients::test_serialize();
 }
 
+#[test]
+fn test_serialize_connections() {
+    Connections::test_serialize();
+}
+
+#[test]
+fn test_serialize_files() {
+    Files::test_serialize();
+}
+
+#[test]
+fn test_serialize_messaging() {
+    Messaging::test_serialize();
+}
+
 #[test]
 fn test_serialize_storages() {
     Storages::test_serialize();
@@ -3,14 +3,60 @@ use std::path::PathBuf;
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Storages {
+    /// Index of the storage that answers reads, all storages receive writes
+    #[serde(default)]
+    pub primary: usize,
     #[serde(rename = "storage")]
     pub storages: Vec<Storage>,
+    /// Applies to conversations whose contact and host address don't set their own
+    #[serde(default)]
+    pub retention: Retention,
+}
+
+/// How long messages of a conversation are kept
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Retention {
+    #[default]
+    Forever,
+    Days {
+        days: u32,
+    },
+    /// Keeps only the newest messages
+    Messages {
+        count: u32,
+    },
 }
 
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(tag = "kind", rename_all = "snake_case")]
 pub enum Storage {
-    Sqlite { path: PathBuf },
+    #[cfg(feature = "storage-sqlite")]
+    Sqlite {
+        path: PathBuf,
+        #[serde(default)]
+        encrypted: bool,
+    },
+    #[cfg(feature = "storage-postgres")]
+    Postgres {
+        url: String,
+        #[serde(default)]
+        encrypted: bool,
+    },
+    Memory,
+}
+
+impl Storage {
+    /// Whether stored messages are encrypted with a key derived from the startup passphrase
+    pub fn encrypted(&self) -> bool {
+        match self {
+            #[cfg(feature = "storage-sqlite")]
+            Storage::Sqlite { encrypted, .. } => *encrypted,
+            #[cfg(feature = "storage-postgres")]
+            Storage::Postgres { encrypted, .. } => *encrypted,
+            Storage::Memory => false,
+        }
+    }
 }
 
 impl super::ConfigTrait for Storages {
@@ -22,9 +68,17 @@ impl super::ConfigTrait for Storages {
 impl Default for Storages {
     fn default() -> Self {
         Storages {
-            storages: vec![Storage::Sqlite {
-                path: "data/sqlite.db".parse().unwrap(),
-            }],
+            primary: 0,
+            storages: vec![
+                #[cfg(feature = "storage-sqlite")]
+                Storage::Sqlite {
+                    path: "data/sqlite.db".parse().unwrap(),
+                    encrypted: false,
+                },
+                #[cfg(not(feature = "storage-sqlite"))]
+                Storage::Memory,
+            ],
+            retention: Retention::Forever,
         }
     }
 }
@@ -1,6 +1,6 @@
 use std::{fs::remove_file, os::unix::net::UnixListener, path::PathBuf, sync::Arc};
 
-use ed25519_dalek::Signature;
+use ed25519_dalek::ExpandedSecretKey;
 use futures::{
     future::{ready, TryFutureExt},
     stream::{poll_fn, select_all, StreamExt},
@@ -12,7 +12,9 @@ use tokio::{
 };
 
 use crate::{
+    client::model::PeerHostPair,
     config::Config,
+    crypto::{auth_payload, decapsulate_token, encapsulate_token},
     error::{BlackedoutError, Result},
     secure::SecureStream,
     state::State,
@@ -74,18 +76,16 @@ async fn handle_connection(
     state: &Arc<Mutex<State>>,
     storage: &Arc<Storage>,
 ) {
-    let (mut stream, host_public_key, token) = match ready(stream)
+    let config = state.lock().await.connections.clone();
+
+    let (mut stream, host_public_key, features) = match ready(stream)
         .and_then(|(stream, addr)| {
-            SecureStream::new(stream, true).map_ok(move |stream| (stream, addr))
+            SecureStream::new(stream, true, &config).map_ok(move |stream| (stream, addr))
         })
         .and_then(|(mut stream, addr)| async move {
-            // Send 32-byte token for the peer to sign
-            let token = rand::random();
-            let res = stream
-                .send(BlackPacket::Authenticate(Authenticate::Token(token)))
-                .await;
-
-            res.map(|_| (stream, addr, token))
+            super::hello(&mut stream)
+                .await
+                .map(|features| (stream, addr, features))
         })
         .await
     {
@@ -96,16 +96,11 @@ async fn handle_connection(
         }
     };
 
-    let peer_public_key = match stream
-        .next()
-        .await
-        .ok_or(BlackedoutError::ConnectionClosed)
-        .and_then(|x| x)
-        .and_then(|x| verify_sign(x, token))
-    {
+    let peer_public_key = match authenticate(&mut stream, state, storage, host_public_key).await {
         Ok(x) => x,
-        Err(_e) => {
+        Err(e) => {
             // TODO: Error handler
+            println!("Rejected incoming connection: {:?}", e);
             return;
         }
     };
@@ -116,28 +111,112 @@ async fn handle_connection(
         stream,
         peer_public_key,
         host_public_key,
+        features,
     )
     .await;
 }
 
-fn verify_sign(packet: BlackPacket, token: [u8; 32]) -> Result<PublicKey> {
-    let (pub_key, sig) = match packet {
-        BlackPacket::Authenticate(auth) => match auth {
-            Authenticate::OnionAndSig { pub_key, sig } => (pub_key, sig),
-            _ => {
-                return Err(BlackedoutError::WrongPacketType(
-                    "Expected an Authenticate::OnionAndSig packet".to_string(),
-                ));
-            }
-        },
+/// The dialing peer proves it holds the onion key it claims by signing a token, and its pinned
+/// identity key by getting the token out of the ciphertext first. This side does the same for
+/// the dialer's challenge, so neither relies on the transport to know who is on the other end.
+async fn authenticate(
+    stream: &mut SecureStream<UnixStream>,
+    state: &Mutex<State>,
+    storage: &Arc<Storage>,
+    host_public_key: PublicKey,
+) -> Result<PublicKey> {
+    let (pub_key, kyber_public_key) = match stream
+        .next()
+        .await
+        .ok_or(BlackedoutError::ConnectionClosed)??
+    {
+        BlackPacket::Authenticate(Authenticate::Identity {
+            pub_key,
+            kyber_public_key,
+        }) => (pub_key, kyber_public_key),
         _ => {
             return Err(BlackedoutError::WrongPacketType(
-                "Expected an Authenticate packet".to_string(),
-            ));
+                "Expected an Authenticate::Identity packet".to_string(),
+            ))
         }
     };
 
-    Signature::from_bytes(&sig)
-        .map_err(|_| BlackedoutError::BadSignature)
-        .and_then(|signature| pub_key.verify(&token, &signature).map(|_| pub_key))
+    let pair = PeerHostPair {
+        peer_public_key: pub_key,
+        host_public_key,
+    };
+
+    let (token, ciphertext) = encapsulate_token(&kyber_public_key)?;
+    let (host_secret_key, host_kyber_public_key, host_kyber_secret_key) = {
+        let state = state.lock().await;
+        let onion = &state
+            .addresses
+            .get(&host_public_key)
+            .ok_or(BlackedoutError::HostPublicKeyDoesNotExist)?
+            .onion;
+
+        (
+            ExpandedSecretKey::from_bytes(&onion.secret_key.to_bytes()).unwrap(),
+            onion.kyber_public_key.clone(),
+            onion.kyber_secret_key.clone(),
+        )
+    };
+
+    stream
+        .send(BlackPacket::Authenticate(Authenticate::Token {
+            ciphertext: ciphertext.clone(),
+            kyber_public_key: host_kyber_public_key.clone(),
+        }))
+        .await?;
+
+    let transcript = stream.transcript();
+    let packet = stream
+        .next()
+        .await
+        .ok_or(BlackedoutError::ConnectionClosed)??;
+
+    super::verify_sign(
+        packet,
+        &pub_key,
+        &transcript,
+        &auth_payload(true, &transcript, &kyber_public_key, &ciphertext, &token),
+    )?;
+
+    // Anyone can claim an onion in the identity packet, only its holder gets this far
+    super::check_identity_key(storage, &pair, &kyber_public_key).await?;
+    super::pin_identity_key(storage, &pair, kyber_public_key).await?;
+
+    let challenge = match stream
+        .next()
+        .await
+        .ok_or(BlackedoutError::ConnectionClosed)??
+    {
+        BlackPacket::Authenticate(Authenticate::Challenge { ciphertext }) => ciphertext,
+        _ => {
+            return Err(BlackedoutError::WrongPacketType(
+                "Expected an Authenticate::Challenge packet".to_string(),
+            ))
+        }
+    };
+
+    let challenge_token = decapsulate_token(&challenge, &host_kyber_secret_key)?;
+    let signature = host_public_key.sign(
+        &auth_payload(
+            false,
+            &transcript,
+            &host_kyber_public_key,
+            &challenge,
+            &challenge_token,
+        ),
+        &host_secret_key,
+    );
+
+    stream
+        .send(BlackPacket::Authenticate(Authenticate::Signature {
+            transcript,
+            sig: signature.to_bytes(),
+        }))
+        .await?;
+
+    Ok(pub_key)
 }
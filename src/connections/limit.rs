@@ -0,0 +1,159 @@
+use std::time::{Duration, Instant};
+
+use crate::config::Connections;
+
+/// How far behind a peer may be without counting as over the limits. Reading is paced so a
+/// peer sending steadily at the limit, like a file transfer, stays within this and is never
+/// disconnected.
+const BURST: Duration = Duration::from_secs(1);
+
+/// Limits how much a peer may send. A peer going over briefly is only read from more slowly,
+/// one that stays more than `BURST` behind for longer than `max_throttle` is disconnected.
+pub struct RateLimit {
+    messages: Bucket,
+    bytes: Bucket,
+    max_throttle: Duration,
+    throttled_since: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Limit {
+    Allow,
+    /// Wait this long before reading from the peer again
+    Throttle(Duration),
+    Exceeded,
+}
+
+impl RateLimit {
+    pub fn new(config: &Connections, now: Instant) -> Self {
+        RateLimit {
+            messages: Bucket::new(config.messages_per_second as f64, now),
+            bytes: Bucket::new(config.bytes_per_second as f64, now),
+            max_throttle: Duration::from_secs(config.max_throttle),
+            throttled_since: None,
+        }
+    }
+
+    /// Accounts for a packet of `bytes` received from the peer
+    pub fn check(&mut self, bytes: u64, now: Instant) -> Limit {
+        let wait = self
+            .messages
+            .take(1.0, now)
+            .max(self.bytes.take(bytes as f64, now));
+
+        if wait <= BURST {
+            self.throttled_since = None;
+
+            return match wait.is_zero() {
+                true => Limit::Allow,
+                false => Limit::Throttle(wait),
+            };
+        }
+
+        let since = *self.throttled_since.get_or_insert(now);
+
+        match now + wait - since > self.max_throttle {
+            true => Limit::Exceeded,
+            false => Limit::Throttle(wait),
+        }
+    }
+}
+
+/// Token bucket holding up to a second worth of its rate
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        let rate = rate.max(1.0);
+
+        Bucket {
+            rate,
+            tokens: rate,
+            updated: now,
+        }
+    }
+
+    /// Takes `cost` tokens. Returns how long it takes until the bucket is out of debt.
+    fn take(&mut self, cost: f64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate) - cost;
+        self.updated = now;
+
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+#[test]
+fn rate_limit() {
+    let start = Instant::now();
+    let at = |millis| start + Duration::from_millis(millis);
+    let mut limit = RateLimit::new(
+        &Connections {
+            messages_per_second: 10,
+            bytes_per_second: 1000,
+            max_throttle: 2,
+            ..Default::default()
+        },
+        start,
+    );
+
+    for _ in 0..10 {
+        assert_eq!(limit.check(10, at(0)), Limit::Allow);
+    }
+
+    assert_eq!(
+        limit.check(10, at(0)),
+        Limit::Throttle(Duration::from_millis(100))
+    );
+
+    // Back under the limit after a pause
+    assert_eq!(limit.check(10, at(1000)), Limit::Allow);
+    assert_eq!(
+        limit.check(1490, at(1000)),
+        Limit::Throttle(Duration::from_millis(500))
+    );
+
+    // Flooding keeps the peer throttled until it is cut off
+    let mut now = 1000;
+    let exceeded = (0..100).find(|_| {
+        now += 100;
+        limit.check(200, at(now)) == Limit::Exceeded
+    });
+
+    assert!(exceeded.is_some());
+    assert_eq!(now, 2100);
+}
+
+#[test]
+fn steady_sender() {
+    let start = Instant::now();
+    let mut now = start;
+    let mut limit = RateLimit::new(
+        &Connections {
+            messages_per_second: 10,
+            bytes_per_second: 1000,
+            max_throttle: 2,
+            ..Default::default()
+        },
+        start,
+    );
+
+    // Sends as fast as it is read from, for far longer than `max_throttle`
+    for _ in 0..100 {
+        match limit.check(500, now) {
+            Limit::Allow => {}
+            Limit::Throttle(wait) => now += wait,
+            Limit::Exceeded => panic!("Disconnected a peer sending at the limit"),
+        }
+    }
+
+    assert!(now - start >= Duration::from_secs(49));
+}
@@ -1,9 +1,20 @@
 pub mod incoming;
+pub mod limit;
 pub mod model;
+pub mod outbox;
 pub mod outgoing;
+pub mod transfer;
 
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
+use ed25519_dalek::{ExpandedSecretKey, Signature};
 use futures::{
     future::{select, Either},
     stream::StreamExt,
@@ -15,19 +26,149 @@ use tokio::{
         mpsc::{channel, Receiver},
         Mutex,
     },
+    task::spawn_blocking,
+    time::MissedTickBehavior,
 };
-use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::wrappers::{IntervalStream, ReceiverStream};
 
 use crate::{
-    client::model::{ClientPacket, PeerHostPair},
-    error::BlackedoutError,
+    client::model::{ClientPacket, PeerHostPair, RoundTrip},
+    error::{BlackedoutError, Result},
     secure::SecureStream,
     state::State,
     storage::Storage,
     types::PublicKey,
 };
 
-use self::model::{BlackPacket, Data};
+use self::limit::{Limit, RateLimit};
+use self::model::{
+    Ack, Authenticate, BlackPacket, Data, Envelope, Feature, Hello, Reject, Transfer,
+};
+
+/// What the rest of the node sends through a connection
+pub enum ToPeer {
+    /// Data along with the token of the client that sent it. Read receipts have none and are
+    /// never confirmed.
+    Data(Option<[u8; 12]>, Envelope),
+    Transfer(Transfer),
+}
+
+/// Typing indicators sent to a peer are spaced at least this far apart
+const TYPING_MIN_INTERVAL: Duration = Duration::from_millis(250);
+/// An unchanged typing state is only sent again after this long
+const TYPING_REFRESH: Duration = Duration::from_secs(3);
+
+/// Exchanges `Hello`s right after the secure channel is set up. Returns the features both sides
+/// support, peers that are too old are turned away.
+pub async fn hello<S>(stream: &mut SecureStream<S>) -> Result<Vec<Feature>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let hello = Hello::ours();
+
+    stream.send(BlackPacket::Hello(hello.clone())).await?;
+
+    match stream
+        .next()
+        .await
+        .ok_or(BlackedoutError::ConnectionClosed)??
+    {
+        BlackPacket::Hello(peer) => {
+            let features = hello.negotiate(&peer)?;
+
+            if features.contains(&Feature::Rekey) {
+                stream.enable_rekeying();
+            }
+
+            Ok(features)
+        }
+        _ => Err(BlackedoutError::WrongPacketType(
+            "Expected a Hello packet".to_string(),
+        )),
+    }
+}
+
+/// Checks the other side's `Authenticate::Signature`. A peer that saw another transcript is on
+/// another channel, most likely one relayed by someone in between, which is told apart from a
+/// plain bad signature.
+pub fn verify_sign(
+    packet: BlackPacket,
+    pub_key: &PublicKey,
+    transcript: &[u8; 32],
+    payload: &[u8; 32],
+) -> Result<()> {
+    let sig = match packet {
+        BlackPacket::Authenticate(Authenticate::Signature {
+            transcript: peer_transcript,
+            sig,
+        }) => {
+            if peer_transcript != *transcript {
+                return Err(BlackedoutError::TranscriptMismatch);
+            }
+
+            sig
+        }
+        _ => {
+            return Err(BlackedoutError::WrongPacketType(
+                "Expected an Authenticate::Signature packet".to_string(),
+            ))
+        }
+    };
+
+    Signature::from_bytes(&sig)
+        .map_err(|_| BlackedoutError::BadSignature)
+        .and_then(|signature| pub_key.verify(payload, &signature))
+}
+
+/// Fails with `IdentityKeyChanged` if a peer presents another identity key than the one pinned
+/// for it, warning every client. Only call this once the peer signed for the key, otherwise
+/// anyone could raise the warning.
+pub async fn check_identity_key(
+    storage: &Arc<Storage>,
+    pair: &PeerHostPair,
+    key: &[u8],
+) -> Result<()> {
+    let pinned = {
+        let storage = storage.clone();
+        let pair = pair.clone();
+
+        spawn_blocking(move || storage.identity_key(&pair))
+            .await
+            .map_err(|_| BlackedoutError::Unexpected)??
+    };
+
+    match pinned {
+        Some(pinned) if pinned != key => {
+            println!(
+                "WARNING: {} presented a different identity key than the one pinned for it. \
+                Refusing the connection, someone may be impersonating it.",
+                pair.peer_public_key.to_onion_address()
+            );
+
+            storage
+                .broadcast(ClientPacket::IdentityKeyChanged(pair.clone()))
+                .await;
+
+            Err(BlackedoutError::IdentityKeyChanged)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Pins the identity key of a peer seen for the first time. Only called once it proved to hold
+/// the key so nobody else can pin one for it.
+pub async fn pin_identity_key(
+    storage: &Arc<Storage>,
+    pair: &PeerHostPair,
+    key: Vec<u8>,
+) -> Result<()> {
+    let storage = storage.clone();
+    let pair = pair.clone();
+
+    spawn_blocking(move || storage.pin_identity_key(&pair, &key))
+        .await
+        .map_err(|_| BlackedoutError::Unexpected)?
+}
 
 pub async fn connection_loop<S>(
     state: Arc<Mutex<State>>,
@@ -35,98 +176,684 @@ pub async fn connection_loop<S>(
     stream: SecureStream<S>,
     peer_public_key: PublicKey,
     host_public_key: PublicKey,
+    features: Vec<Feature>,
 ) where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    let (tx, rx): (_, Receiver<([u8; 12], Data)>) = channel(1);
+    let (tx, rx): (_, Receiver<ToPeer>) = channel(1);
 
-    state
-        .lock()
-        .await
-        .addresses
-        .get_mut(&host_public_key)
-        .unwrap()
-        .connected_peers
-        .insert(peer_public_key, tx);
+    let (host_secret_key, config) = {
+        let mut state = state.lock().await;
+        let config = state.connections.clone();
+        let address = state.addresses.get_mut(&host_public_key).unwrap();
 
-    let (mut stream_tx, stream_rx) = stream.split();
+        address.connected_peers.insert(peer_public_key, tx);
 
+        (
+            ExpandedSecretKey::from_bytes(&address.onion.secret_key.to_bytes()).unwrap(),
+            config,
+        )
+    };
+
+    let pair = PeerHostPair {
+        peer_public_key,
+        host_public_key,
+    };
+
+    let received = stream.received();
+    let (mut stream_tx, mut from_peer) = stream.split();
     let mut to_peer = ReceiverStream::new(rx);
-    let mut from_peer = stream_rx.map(|x| {
-        x.and_then(|x| match x {
-            BlackPacket::Data(n) => Ok(n),
-            _ => Err(BlackedoutError::WrongPacketType(
-                "Expected a Data packet".to_string(),
-            )),
-        })
-    });
 
     storage
-        .send_packet(ClientPacket::ConnectionEstablished(PeerHostPair {
-            peer_public_key,
-            host_public_key,
-        }))
+        .send_packet(ClientPacket::ConnectionEstablished(pair.clone()))
         .await;
 
     tokio::spawn(async move {
+        // Envelopes sent to the peer that it hasn't acknowledged yet
+        let mut unacked = HashMap::new();
+        let mut typing = TypingThrottle::default();
+        let mut keepalive = Keepalive::default();
+        let mut limit = RateLimit::new(&config, Instant::now());
+        let mut counted = received.load(Ordering::Relaxed);
+        // Told to clients when the connection ends because of the peer
+        let mut reason = None;
+        let mut pings = {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(config.ping_interval.max(1)));
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            IntervalStream::new(interval)
+        };
+
+        // The peer is already registered, so data queued after this read comes through the
+        // channel. Data that is read here and comes through the channel too is only sent once.
+        let outbox = {
+            let storage = storage.clone();
+            let pair = pair.clone();
+
+            spawn_blocking(move || storage.outbox(Some(&pair))).await
+        };
+
+        match outbox {
+            Ok(Ok(outbox)) => {
+                for entry in outbox {
+                    if stream_tx
+                        .send(BlackPacket::Envelope(entry.envelope.clone()))
+                        .await
+                        .is_err()
+                    {
+                        // TODO: Error handling
+                        break;
+                    }
+
+                    unacked.insert(entry.envelope.message_id, (entry.token, entry.envelope));
+                }
+            }
+            e => println!("Error reading outbox: {:?}", e),
+        }
+
+        let offers = match features.contains(&Feature::FileTransfer) {
+            true => state.lock().await.transfers.offers(&pair),
+            false => Vec::new(),
+        };
+
+        for offer in offers {
+            if stream_tx
+                .send(BlackPacket::Transfer(Transfer::Offer(offer)))
+                .await
+                .is_err()
+            {
+                // TODO: Error handling
+                break;
+            }
+        }
+
+        // Receipts are on unless the peer says otherwise, so it is only told when they're off
+        let receipts = {
+            let storage = storage.clone();
+            let pair = pair.clone();
+
+            spawn_blocking(move || storage.read_receipts(&pair)).await
+        };
+
+        if features.contains(&Feature::ReadReceipts) && matches!(receipts, Ok(Ok(false))) {
+            stream_tx
+                .send(BlackPacket::Envelope(Envelope::new(Data::ReadReceipts {
+                    enabled: false,
+                })))
+                .await
+                .ok();
+        }
+
+        // Whether the peer wants our read receipts
+        let mut peer_receipts = true;
+
         loop {
-            let (data, token) = match select(to_peer.next(), from_peer.next()).await {
+            // Wakes up once a typing state kept by the throttle can be sent
+            let deadline = typing.deadline();
+            let flush = async move {
+                match deadline {
+                    Some(n) => tokio::time::sleep_until(n.into()).await,
+                    None => futures::future::pending().await,
+                }
+            };
+            futures::pin_mut!(flush);
+
+            let packet = match select(
+                to_peer.next(),
+                select(from_peer.next(), select(pings.next(), flush)),
+            )
+            .await
+            {
                 Either::Left((n, _)) => match n {
-                    Some((token, data)) => (data, Some(token)),
+                    Some(ToPeer::Transfer(_)) if !features.contains(&Feature::FileTransfer) => {
+                        continue;
+                    }
+                    Some(ToPeer::Transfer(transfer)) => {
+                        if stream_tx
+                            .send(BlackPacket::Transfer(transfer))
+                            .await
+                            .is_err()
+                        {
+                            // TODO: Error handling
+                        }
+
+                        continue;
+                    }
+                    Some(ToPeer::Data(token, envelope)) => {
+                        if envelope
+                            .data
+                            .feature()
+                            .is_some_and(|x| !features.contains(&x))
+                        {
+                            if let Some(token) = token {
+                                storage
+                                    .broadcast(ClientPacket::SendDataFailed {
+                                        token,
+                                        pair: pair.clone(),
+                                        message_id: envelope.message_id,
+                                    })
+                                    .await;
+                            }
+
+                            continue;
+                        }
+
+                        if matches!(envelope.data, Data::Read(_)) && !peer_receipts {
+                            continue;
+                        }
+
+                        if let Data::Typing { active } = envelope.data {
+                            if !typing.allow(active, Instant::now()) {
+                                continue;
+                            }
+                        }
+
+                        if unacked.contains_key(&envelope.message_id) {
+                            continue;
+                        }
+
+                        match stream_tx
+                            .send(BlackPacket::Envelope(envelope.clone()))
+                            .await
+                        {
+                            // Only queued data is confirmed
+                            Ok(_) => {
+                                if let (Some(token), true) = (token, envelope.data.queued()) {
+                                    unacked.insert(envelope.message_id, (token, envelope));
+                                }
+                            }
+                            Err(_e) => {
+                                // TODO: Error handling
+                            }
+                        }
+
+                        continue;
+                    }
                     None => break,
                 },
-                Either::Right((n, _)) => match n {
-                    Some(Ok(n)) => (n, None),
-                    Some(Err(_)) => {
+                Either::Right((Either::Right((Either::Right(_), _)), _)) => {
+                    if let Some(active) = typing.due(Instant::now()) {
+                        if stream_tx
+                            .send(BlackPacket::Envelope(Envelope::new(Data::Typing {
+                                active,
+                            })))
+                            .await
+                            .is_err()
+                        {
+                            // TODO: Error handling
+                            break;
+                        }
+                    }
+
+                    continue;
+                }
+                Either::Right((Either::Right((Either::Left(_), _)), _)) => {
+                    if !features.contains(&Feature::Keepalive) {
+                        continue;
+                    }
+
+                    let nonce = match keepalive.tick(Instant::now(), config.max_missed_pings) {
+                        Some(n) => n,
+                        None => {
+                            reason = Some(BlackedoutError::PingTimeout);
+                            break;
+                        }
+                    };
+
+                    if stream_tx.send(BlackPacket::Ping(nonce)).await.is_err() {
                         // TODO: Error handling
-                        // The peer sent a wrong packet type so disconnect it here
                         break;
                     }
-                    None => break,
-                },
-            };
 
-            let packet = match token {
-                Some(token) => {
-                    match stream_tx.send(BlackPacket::Data(data.clone())).await {
-                        Ok(_) => {}
-                        Err(_e) => {
+                    continue;
+                }
+                Either::Right((Either::Left((n, _)), _)) => match n {
+                    // Checked for every packet, the arms below only see the ones within limits
+                    Some(Ok(_)) if !throttle(&mut limit, &received, &mut counted).await => {
+                        reason = Some(BlackedoutError::RateLimited);
+                        break;
+                    }
+                    // Left for a newer version, the peer only sends them when it is wrong about ours
+                    Some(Ok(BlackPacket::Unknown)) => continue,
+                    Some(Ok(BlackPacket::Ping(nonce))) => {
+                        if stream_tx.send(BlackPacket::Pong(nonce)).await.is_err() {
                             // TODO: Error handling
+                            break;
+                        }
+
+                        continue;
+                    }
+                    Some(Ok(BlackPacket::Pong(nonce))) => {
+                        let rtt = match keepalive.pong(nonce, Instant::now()) {
+                            Some(n) => n,
+                            None => continue,
+                        };
+
+                        state
+                            .lock()
+                            .await
+                            .addresses
+                            .get_mut(&host_public_key)
+                            .unwrap()
+                            .round_trips
+                            .insert(peer_public_key, rtt);
+
+                        storage
+                            .broadcast(ClientPacket::RoundTrip(RoundTrip {
+                                pair: pair.clone(),
+                                millis: rtt.as_millis() as u64,
+                            }))
+                            .await;
+
+                        continue;
+                    }
+                    Some(Ok(BlackPacket::Envelope(Envelope {
+                        data: Data::ReadReceipts { enabled },
+                        ..
+                    }))) => {
+                        peer_receipts = enabled;
+                        continue;
+                    }
+                    Some(Ok(BlackPacket::Envelope(envelope))) if envelope.data.ephemeral() => {
+                        storage
+                            .broadcast(ClientPacket::DataReceived {
+                                pair: pair.clone(),
+                                envelope,
+                            })
+                            .await;
+                        continue;
+                    }
+                    Some(Ok(BlackPacket::Envelope(envelope))) => {
+                        // Data referring to an earlier message is stored before it is
+                        // acknowledged, so the sender learns when it isn't allowed to
+                        let checked = match envelope.data.target() {
+                            Some(_) => Some(
+                                storage
+                                    .receive_checked(pair.clone(), envelope.clone())
+                                    .await,
+                            ),
+                            None => None,
+                        };
+
+                        let reply = match &checked {
+                            Some(Err(e)) => {
+                                println!("Rejected data from peer: {:?}", e);
+
+                                BlackPacket::Reject(Reject {
+                                    message_id: envelope.message_id,
+                                })
+                            }
+                            _ => {
+                                let sig =
+                                    host_public_key.sign(&envelope.ack_payload(), &host_secret_key);
+
+                                BlackPacket::Ack(Ack {
+                                    message_id: envelope.message_id,
+                                    sig: sig.to_bytes(),
+                                })
+                            }
+                        };
+
+                        if stream_tx.send(reply).await.is_err() {
+                            // TODO: Error handling
+                            break;
+                        }
+
+                        if checked.is_some() {
                             continue;
                         }
+
+                        match &envelope.data {
+                            Data::Read(message_ids) => {
+                                let storage = storage.clone();
+                                let pair = pair.clone();
+                                let message_ids = message_ids.clone();
+
+                                match spawn_blocking(move || {
+                                    storage.read_receipts(&pair).map(|x| {
+                                        x.then_some(ClientPacket::ReadReceipt { pair, message_ids })
+                                    })
+                                })
+                                .await
+                                {
+                                    Ok(Ok(Some(n))) => n,
+                                    // Receipts are off for this peer so theirs aren't shown either
+                                    _ => continue,
+                                }
+                            }
+                            _ => ClientPacket::DataReceived {
+                                pair: pair.clone(),
+                                envelope,
+                            },
+                        }
                     }
+                    Some(Ok(BlackPacket::Transfer(transfer))) => {
+                        if let Some(reply) =
+                            transfer::handle(&state, &storage, &pair, transfer).await
+                        {
+                            if stream_tx.send(BlackPacket::Transfer(reply)).await.is_err() {
+                                // TODO: Error handling
+                                break;
+                            }
+                        }
 
-                    ClientPacket::SendDataConfirmation { token }
-                }
-                None => ClientPacket::DataReceived {
-                    pair: PeerHostPair {
-                        peer_public_key,
-                        host_public_key,
-                    },
-                    data,
+                        continue;
+                    }
+                    Some(Ok(BlackPacket::Ack(ack))) => {
+                        let (token, envelope) = match unacked.remove(&ack.message_id) {
+                            Some(n) => n,
+                            None => continue,
+                        };
+
+                        if let Err(e) = verify_ack(&peer_public_key, &envelope, &ack) {
+                            println!("Rejected delivery ack: {:?}", e);
+                            unacked.insert(ack.message_id, (token, envelope));
+                            continue;
+                        }
+
+                        ClientPacket::SendDataConfirmation {
+                            token,
+                            pair: pair.clone(),
+                            envelope,
+                        }
+                    }
+                    Some(Ok(BlackPacket::Reject(reject))) => {
+                        let (token, _) = match unacked.remove(&reject.message_id) {
+                            Some(n) => n,
+                            None => continue,
+                        };
+
+                        ClientPacket::SendDataFailed {
+                            token,
+                            pair: pair.clone(),
+                            message_id: reject.message_id,
+                        }
+                    }
+                    Some(Err(e)) => {
+                        reason = Some(e);
+                        break;
+                    }
+                    Some(_) => {
+                        // TODO: Error handling
+                        // The peer sent a wrong packet type so disconnect it here
+                        break;
+                    }
+                    None => break,
                 },
             };
 
             storage.send_packet(packet).await;
         }
 
-        state
-            .lock()
-            .await
-            .addresses
-            .get_mut(&host_public_key)
-            .unwrap()
-            .connected_peers
-            .remove(&peer_public_key);
+        {
+            let mut state = state.lock().await;
+            let address = state.addresses.get_mut(&host_public_key).unwrap();
+
+            address.connected_peers.remove(&peer_public_key);
+            address.round_trips.remove(&peer_public_key);
+        }
+
+        // Queued data is sent again on the next connection, the rest is lost
+        let mut to_peer = to_peer.into_inner();
+        to_peer.close();
+
+        let mut failed = unacked
+            .into_values()
+            .map(|(token, envelope)| (Some(token), envelope))
+            .collect::<Vec<_>>();
+
+        while let Ok(n) = to_peer.try_recv() {
+            if let ToPeer::Data(token, envelope) = n {
+                failed.push((token, envelope));
+            }
+        }
+
+        for (token, envelope) in failed {
+            if let (Some(token), false) = (token, envelope.data.queued()) {
+                storage
+                    .broadcast(ClientPacket::SendDataFailed {
+                        token,
+                        pair: pair.clone(),
+                        message_id: envelope.message_id,
+                    })
+                    .await;
+            }
+        }
 
-        to_peer.into_inner().close();
         stream_tx.close().await.ok();
 
+        if let Some(e) = &reason {
+            println!("Disconnected peer: {:?}", e);
+        }
+
         storage
-            .send_packet(ClientPacket::Disconnected(PeerHostPair {
-                peer_public_key,
-                host_public_key,
-            }))
+            .send_packet(ClientPacket::Disconnected {
+                pair,
+                reason: reason.map(|e| e.to_string()),
+            })
             .await;
     });
 }
+
+/// Accounts for the packet just read from the peer and waits out any throttling. Returns
+/// `false` once the peer stayed over its limits for too long.
+async fn throttle(limit: &mut RateLimit, received: &AtomicU64, counted: &mut u64) -> bool {
+    let total = received.load(Ordering::Relaxed);
+    let bytes = total - *counted;
+    *counted = total;
+
+    match limit.check(bytes, Instant::now()) {
+        Limit::Allow => true,
+        Limit::Throttle(wait) => {
+            tokio::time::sleep(wait).await;
+            true
+        }
+        Limit::Exceeded => false,
+    }
+}
+
+/// Limits the typing indicators sent to a peer. A state change goes through once the minimum
+/// interval passed, an unchanged state only as a refresh. A change that comes too soon is kept
+/// and sent late so the peer always ends up with the latest state.
+#[derive(Default)]
+struct TypingThrottle {
+    last: Option<(bool, Instant)>,
+    pending: Option<bool>,
+}
+
+impl TypingThrottle {
+    fn allow(&mut self, active: bool, now: Instant) -> bool {
+        let allowed = self.last.is_none_or(|(last, at)| {
+            let elapsed = now.duration_since(at);
+
+            elapsed >= TYPING_MIN_INTERVAL && (last != active || elapsed >= TYPING_REFRESH)
+        });
+
+        match allowed {
+            true => {
+                self.last = Some((active, now));
+                self.pending = None;
+            }
+            // Nothing is owed if the peer already shows this state
+            false => self.pending = self.last.filter(|x| x.0 != active).map(|_| active),
+        }
+
+        allowed
+    }
+
+    /// When the kept change can be sent
+    fn deadline(&self) -> Option<Instant> {
+        self.pending
+            .and(self.last)
+            .map(|(_, at)| at + TYPING_MIN_INTERVAL)
+    }
+
+    /// Takes the kept change once it can be sent
+    fn due(&mut self, now: Instant) -> Option<bool> {
+        if self.deadline()? > now {
+            return None;
+        }
+
+        let active = self.pending.take()?;
+        self.last = Some((active, now));
+        Some(active)
+    }
+}
+
+/// Tracks pings of a connection. Tor circuits can die without either side noticing, so a peer
+/// that stops answering is dropped rather than kept as connected.
+#[derive(Default)]
+struct Keepalive {
+    /// The latest ping and when it was sent
+    pending: Option<(u32, Instant)>,
+    missed: u32,
+}
+
+impl Keepalive {
+    /// Returns the nonce of the next ping, or `None` once `max_missed` pings in a row went
+    /// unanswered
+    fn tick(&mut self, now: Instant, max_missed: u32) -> Option<u32> {
+        if self.pending.is_some() {
+            self.missed += 1;
+        }
+
+        if self.missed >= max_missed.max(1) {
+            return None;
+        }
+
+        let nonce = rand::random();
+        self.pending = Some((nonce, now));
+
+        Some(nonce)
+    }
+
+    /// Any pong shows the peer is alive, only one for the latest ping gives a round-trip time
+    fn pong(&mut self, nonce: u32, now: Instant) -> Option<Duration> {
+        self.missed = 0;
+
+        let (_, sent) = self.pending.filter(|(x, _)| *x == nonce)?;
+        self.pending = None;
+
+        Some(now.duration_since(sent))
+    }
+}
+
+/// Checks that the peer signed the ack for this exact envelope
+fn verify_ack(peer_public_key: &PublicKey, envelope: &Envelope, ack: &Ack) -> Result<()> {
+    Signature::from_bytes(&ack.sig)
+        .map_err(|_| BlackedoutError::BadSignature)
+        .and_then(|signature| peer_public_key.verify(&envelope.ack_payload(), &signature))
+}
+
+#[test]
+fn delivery_ack() {
+    use crate::connections::model::Data;
+
+    let secret_key =
+        ExpandedSecretKey::from(&ed25519_dalek::SecretKey::from_bytes(&[7; 32]).unwrap());
+    let public_key =
+        PublicKey::from_bytes(ed25519_dalek::PublicKey::from(&secret_key).as_bytes()).unwrap();
+
+    let envelope = Envelope::new(Data::Message("hello".to_string()));
+    let ack = Ack {
+        message_id: envelope.message_id,
+        sig: public_key
+            .sign(&envelope.ack_payload(), &secret_key)
+            .to_bytes(),
+    };
+
+    assert!(verify_ack(&public_key, &envelope, &ack).is_ok());
+
+    let other = Envelope::new(Data::Message("hello".to_string()));
+    assert!(verify_ack(&public_key, &other, &ack).is_err());
+}
+
+#[test]
+fn typing_throttle() {
+    let start = Instant::now();
+    let at = |millis| start + Duration::from_millis(millis);
+    let mut typing = TypingThrottle::default();
+
+    assert!(typing.allow(true, at(0)));
+    assert!(!typing.allow(false, at(100)));
+    assert_eq!(typing.deadline(), Some(at(250)));
+    assert_eq!(typing.due(at(200)), None);
+    assert_eq!(typing.due(at(250)), Some(false));
+    assert_eq!(typing.due(at(260)), None);
+    assert!(typing.allow(true, at(600)));
+    assert!(!typing.allow(false, at(650)));
+    // Changing back before the kept change was sent leaves nothing to send
+    assert!(!typing.allow(true, at(700)));
+    assert_eq!(typing.deadline(), None);
+    assert!(!typing.allow(true, at(900)));
+    assert!(typing.allow(false, at(900)));
+    assert!(typing.allow(true, at(1200)));
+    assert!(!typing.allow(true, at(3600)));
+    assert!(typing.allow(true, at(4200)));
+}
+
+#[test]
+fn keepalive() {
+    let start = Instant::now();
+    let at = |millis| start + Duration::from_millis(millis);
+    let mut keepalive = Keepalive::default();
+
+    let first = keepalive.tick(at(0), 3).unwrap();
+    assert_eq!(
+        keepalive.pong(first, at(800)),
+        Some(Duration::from_millis(800))
+    );
+    assert_eq!(keepalive.pong(first, at(900)), None);
+
+    let second = keepalive.tick(at(1000), 3).unwrap();
+    keepalive.tick(at(2000), 3).unwrap();
+
+    // A late pong keeps the connection alive without a round-trip time
+    assert_eq!(keepalive.pong(second, at(2500)), None);
+    keepalive.tick(at(3000), 3).unwrap();
+    keepalive.tick(at(4000), 3).unwrap();
+    assert_eq!(keepalive.tick(at(5000), 3), None);
+}
+
+#[test]
+fn transcript_binding() {
+    use crate::crypto::auth_payload;
+
+    let secret_key = ed25519_dalek::ExpandedSecretKey::from(
+        &ed25519_dalek::SecretKey::from_bytes(&[7; 32]).unwrap(),
+    );
+    let public_key =
+        PublicKey::from_bytes(ed25519_dalek::PublicKey::from(&secret_key).as_bytes()).unwrap();
+
+    let payload = auth_payload(true, &[1; 32], &[2; 8], &[3; 8], &[4; 32]);
+    let signature = |transcript, payload: &[u8; 32]| {
+        BlackPacket::Authenticate(Authenticate::Signature {
+            transcript,
+            sig: public_key.sign(payload, &secret_key).to_bytes(),
+        })
+    };
+
+    assert!(verify_sign(
+        signature([1; 32], &payload),
+        &public_key,
+        &[1; 32],
+        &payload
+    )
+    .is_ok());
+    assert!(matches!(
+        verify_sign(
+            signature([9; 32], &payload),
+            &public_key,
+            &[1; 32],
+            &payload
+        ),
+        Err(BlackedoutError::TranscriptMismatch)
+    ));
+
+    // Signed for another channel but claiming this one
+    let other = auth_payload(true, &[9; 32], &[2; 8], &[3; 8], &[4; 32]);
+    assert!(matches!(
+        verify_sign(signature([1; 32], &other), &public_key, &[1; 32], &payload),
+        Err(BlackedoutError::SignatureVerificationFailed)
+    ));
+}
@@ -1,28 +1,416 @@
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
+use serde_with::{base64::Base64, serde_as, Bytes};
 
-use crate::types::PublicKey;
+use sha3::{Digest, Sha3_256};
+use strum::{EnumVariantNames, VariantNames};
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+use crate::{
+    error::{BlackedoutError, Result},
+    types::{MessageId, PublicKey},
+};
+
+#[derive(Clone, Debug, Deserialize, Serialize, EnumVariantNames)]
 #[serde(rename_all = "snake_case", tag = "kind", content = "data")]
+#[strum(serialize_all = "snake_case")]
 pub enum BlackPacket {
+    /// Sent by both sides right after the secure channel is set up
+    Hello(Hello),
     Authenticate(Authenticate),
-    Data(Data),
+    Envelope(Envelope),
+    Ack(Ack),
+    Reject(Reject),
+    Transfer(Transfer),
+    /// Answered with a `Pong` carrying the same nonce
+    Ping(u32),
+    Pong(u32),
+    /// Handled by `SecureStream` itself and never seen outside of it
+    Rekey(Rekey),
+    /// A packet, or the data of an envelope, of a kind added by a newer version. Never sent.
+    #[serde(skip)]
+    Unknown,
+}
+
+impl BlackPacket {
+    /// Kinds this node doesn't know decode to `Unknown` so the connection can ignore them.
+    /// Malformed packets of known kinds still fail.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let e = match bson::from_slice(bytes) {
+            Ok(n) => return Ok(n),
+            Err(e) => e,
+        };
+
+        let document = bson::from_slice::<bson::Document>(bytes)?;
+        let known = |document: &bson::Document, variants: &[&str]| {
+            document
+                .get_str("kind")
+                .map_or(true, |x| variants.contains(&x))
+        };
+
+        let known = match document.get_str("kind") {
+            Ok("envelope") => document
+                .get_document("data")
+                .and_then(|x| x.get_document("data"))
+                .map_or(true, |x| known(x, Data::VARIANTS)),
+            _ => known(&document, BlackPacket::VARIANTS),
+        };
+
+        match known {
+            true => Err(e.into()),
+            false => Ok(BlackPacket::Unknown),
+        }
+    }
+}
+
+/// Bumped whenever a change to the protocol can't be covered by a feature
+pub const PROTOCOL_VERSION: u32 = 6;
+/// The oldest version this node still talks to
+pub const MIN_PROTOCOL_VERSION: u32 = 6;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Hello {
+    pub version: u32,
+    pub features: Vec<Feature>,
 }
 
+impl Hello {
+    /// What this node sends
+    pub fn ours() -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            features: FEATURES.to_vec(),
+        }
+    }
+
+    /// The features both sides can use, given the peer's hello
+    pub fn negotiate(&self, peer: &Hello) -> Result<Vec<Feature>> {
+        if peer.version < MIN_PROTOCOL_VERSION {
+            return Err(BlackedoutError::UnsupportedVersion);
+        }
+
+        Ok(self
+            .features
+            .iter()
+            .filter(|x| peer.features.contains(x))
+            .copied()
+            .collect())
+    }
+}
+
+/// Optional parts of the protocol a node understands. Each is only used once both sides
+/// advertised it.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    Typing,
+    ReadReceipts,
+    FileTransfer,
+    /// Peers are pinged and dropped once they stop answering
+    Keepalive,
+    /// Each side replaces its sending key now and then
+    Rekey,
+    /// A feature of a newer version
+    #[serde(other)]
+    Unknown,
+}
+
+/// Everything this node supports
+pub const FEATURES: &[Feature] = &[
+    Feature::Typing,
+    Feature::ReadReceipts,
+    Feature::FileTransfer,
+    Feature::Keepalive,
+    Feature::Rekey,
+];
+
+/// Sent in order once the `Hello`s are exchanged
+#[serde_as]
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case", tag = "kind", content = "data")]
 pub enum Authenticate {
-    Token([u8; 32]),
-    OnionAndSig {
+    /// Who the dialing side claims to be
+    Identity {
         pub_key: PublicKey,
+        #[serde_as(as = "Bytes")]
+        kyber_public_key: Vec<u8>,
+    },
+    /// A token encapsulated to the dialer's identity key, along with the listener's own key
+    Token {
+        #[serde_as(as = "Bytes")]
+        ciphertext: Vec<u8>,
+        #[serde_as(as = "Bytes")]
+        kyber_public_key: Vec<u8>,
+    },
+    /// Sent by the dialer after its signature, a token encapsulated to the listener's identity
+    /// key. The dialer checks the listener's answer itself rather than trusting Tor with its
+    /// identity.
+    Challenge {
+        #[serde_as(as = "Bytes")]
+        ciphertext: Vec<u8>,
+    },
+    /// The transcript hash and the onion key's signature of `crypto::auth_payload` for the token
+    /// the other side sent. Sent by the dialer for `Token` and by the listener for `Challenge`.
+    Signature {
+        transcript: [u8; 32],
         #[serde(with = "BigArray")]
         sig: [u8; 64],
     },
 }
 
+/// Replaces the key of one direction. The sender asks for a fresh Kyber exchange, and once it
+/// has the ciphertext sends `Switch` as the last packet under its old key.
+#[serde_as]
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case", tag = "kind", content = "data")]
+pub enum Rekey {
+    Request {
+        #[serde_as(as = "Bytes")]
+        public_key: Vec<u8>,
+    },
+    Response {
+        #[serde_as(as = "Bytes")]
+        ciphertext: Vec<u8>,
+    },
+    Switch,
+}
+
+/// Wraps everything sent to a peer. The content type is the `kind` of `data`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Envelope {
+    pub message_id: MessageId,
+    /// Milliseconds since the epoch on the sender's clock
+    pub sent_at: i64,
+    pub data: Data,
+}
+
+impl Envelope {
+    pub fn new(data: Data) -> Self {
+        Envelope {
+            message_id: MessageId::random(),
+            sent_at: chrono::Utc::now().timestamp_millis(),
+            data,
+        }
+    }
+
+    /// What the recipient signs to acknowledge the envelope
+    pub fn ack_payload(&self) -> Vec<u8> {
+        let mut payload = b"blackedout delivery ack".to_vec();
+        payload.extend_from_slice(self.message_id.as_bytes());
+        payload.extend_from_slice(&self.sent_at.to_be_bytes());
+        payload.extend_from_slice(&Sha3_256::digest(bson::to_vec(&self.data).unwrap()));
+        payload
+    }
+}
+
+/// Sent back by the recipient once an envelope has arrived, signed with its onion key
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Ack {
+    pub message_id: MessageId,
+    #[serde(with = "BigArray")]
+    pub sig: [u8; 64],
+}
+
+/// Sent back instead of an `Ack` for an envelope the recipient refused, like an edit of a
+/// message that isn't the sender's. The sender drops it and tells its clients.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Reject {
+    pub message_id: MessageId,
+}
+
+/// Files are sent outside of envelopes so chunks aren't stored or acknowledged one by one
+#[serde_as]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "kind", content = "data")]
+pub enum Transfer {
+    /// Sent again on every connection until the receiver completes or rejects it
+    Offer(FileOffer),
+    /// Asks for the file from `offset`, which is how much the receiver kept of earlier attempts
+    Accept {
+        transfer_id: MessageId,
+        offset: u64,
+    },
+    Reject {
+        transfer_id: MessageId,
+    },
+    Chunk {
+        transfer_id: MessageId,
+        offset: u64,
+        #[serde_as(as = "Bytes")]
+        data: Vec<u8>,
+    },
+    /// Sent by the receiver once it has every byte, `verified` if the hash matched
+    Complete {
+        transfer_id: MessageId,
+        verified: bool,
+    },
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct FileOffer {
+    pub transfer_id: MessageId,
+    pub name: String,
+    pub size: u64,
+    /// SHA3-256 of the whole file
+    #[serde_as(as = "Base64")]
+    pub hash: [u8; 32],
+    pub mime: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, EnumVariantNames)]
+#[serde(rename_all = "snake_case", tag = "kind", content = "data")]
+#[strum(serialize_all = "snake_case")]
 pub enum Data {
     Message(String),
+    /// A message answering an earlier one, optionally quoting part of it
+    Reply {
+        reply_to: MessageId,
+        #[serde(default)]
+        quote: Option<String>,
+        body: String,
+    },
+    /// The recipient displayed these incoming messages
+    Read(Vec<MessageId>),
+    /// Whether the sender sends read receipts. Receipts aren't sent to a peer that turned them
+    /// off since it doesn't show those it gets either.
+    ReadReceipts {
+        enabled: bool,
+    },
+    /// Refreshed every few seconds while active. Clients should expire `active` without a
+    /// refresh since the sender may have gone away.
+    Typing {
+        active: bool,
+    },
+    /// Replaces the body of an earlier message of the sender. Every edit stays in the history.
+    Edit {
+        target_id: MessageId,
+        new_body: String,
+    },
+    /// Deletes an earlier message of the sender along with its edits. This stays in the
+    /// history as a tombstone.
+    Delete {
+        target_id: MessageId,
+    },
+    /// Adds an emoji reaction to a message of either side, or takes it back with `remove`. An
+    /// emoji is at most 32 bytes and each side keeps at most 20 per message.
+    Reaction {
+        target_id: MessageId,
+        emoji: String,
+        #[serde(default)]
+        remove: bool,
+    },
+}
+
+impl Data {
+    /// Whether the data belongs in the conversation history
+    pub fn stored(&self) -> bool {
+        match self {
+            Data::Message(_) | Data::Reply { .. } | Data::Edit { .. } | Data::Delete { .. } => true,
+            // Reactions are kept apart and grouped per message
+            Data::Read(_)
+            | Data::ReadReceipts { .. }
+            | Data::Typing { .. }
+            | Data::Reaction { .. } => false,
+        }
+    }
+
+    /// Ephemeral data is neither acknowledged nor stored, only shown to clients
+    pub fn ephemeral(&self) -> bool {
+        matches!(self, Data::Typing { .. } | Data::ReadReceipts { .. })
+    }
+
+    /// Text that full-text search looks at
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            Data::Message(body) | Data::Reply { body, .. } | Data::Edit { new_body: body, .. } => {
+                Some(body)
+            }
+            Data::Read(_)
+            | Data::ReadReceipts { .. }
+            | Data::Typing { .. }
+            | Data::Delete { .. }
+            | Data::Reaction { .. } => None,
+        }
+    }
+
+    /// Whether the data waits in the outbox until the peer acknowledges it. Anything else is
+    /// only sent while connected, and fails when the connection ends first.
+    pub fn queued(&self) -> bool {
+        !matches!(
+            self,
+            Data::Read(_) | Data::ReadReceipts { .. } | Data::Typing { .. }
+        )
+    }
+
+    /// The feature the peer needs to understand this. Without it the data isn't sent.
+    pub fn feature(&self) -> Option<Feature> {
+        match self {
+            Data::Typing { .. } => Some(Feature::Typing),
+            Data::Read(_) | Data::ReadReceipts { .. } => Some(Feature::ReadReceipts),
+            _ => None,
+        }
+    }
+
+    /// The earlier message this refers to
+    pub fn target(&self) -> Option<&MessageId> {
+        match self {
+            Data::Edit { target_id, .. }
+            | Data::Delete { target_id }
+            | Data::Reaction { target_id, .. } => Some(target_id),
+            Data::Message(_)
+            | Data::Reply { .. }
+            | Data::Read(_)
+            | Data::ReadReceipts { .. }
+            | Data::Typing { .. } => None,
+        }
+    }
+}
+
+#[test]
+fn hello() {
+    let peer = Hello {
+        version: PROTOCOL_VERSION + 1,
+        features: vec![Feature::FileTransfer, Feature::Unknown, Feature::Typing],
+    };
+
+    assert_eq!(
+        Hello::ours().negotiate(&peer).unwrap(),
+        [Feature::Typing, Feature::FileTransfer]
+    );
+
+    let peer: Hello = bson::from_slice(
+        &bson::to_vec(&bson::doc! { "version": 0, "features": ["holograms"] }).unwrap(),
+    )
+    .unwrap();
+
+    assert_eq!(peer.features, [Feature::Unknown]);
+    assert!(matches!(
+        Hello::ours().negotiate(&peer),
+        Err(BlackedoutError::UnsupportedVersion)
+    ));
+}
+
+#[test]
+fn unknown_kinds() {
+    let decode = |x: bson::Document| BlackPacket::decode(&bson::to_vec(&x).unwrap());
+
+    assert!(matches!(
+        decode(bson::doc! { "kind": "hologram", "data": { "frames": [1, 2, 3] } }),
+        Ok(BlackPacket::Unknown)
+    ));
+    assert!(matches!(
+        decode(bson::doc! {
+            "kind": "envelope",
+            "data": { "sent_at": 0, "data": { "kind": "hologram", "data": [] } },
+        }),
+        Ok(BlackPacket::Unknown)
+    ));
+    assert!(matches!(
+        decode(bson::doc! { "kind": "ping", "data": "not a nonce" }),
+        Err(BlackedoutError::BsonError(_))
+    ));
+    assert!(matches!(
+        decode(bson::doc! { "kind": "ping", "data": 7 }),
+        Ok(BlackPacket::Ping(7))
+    ));
 }
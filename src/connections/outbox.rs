@@ -0,0 +1,154 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::{
+        mpsc::{channel, Sender},
+        Mutex,
+    },
+    task::spawn_blocking,
+};
+
+use crate::{
+    client::model::PeerHostPair,
+    error::{BlackedoutError, Result},
+    state::State,
+    storage::Storage,
+    types::PublicKey,
+};
+
+/// How often the outbox is checked for peers that aren't connected
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Delay after the first failed dial, doubled after every further failure
+const RETRY_MIN: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(10 * 60);
+
+/// Dials peers with queued data until they are connected. `connection_loop` flushes the outbox
+/// once a connection is registered. Dials run concurrently so an unreachable peer doesn't hold
+/// up the others.
+pub async fn start_dialer(
+    state: &Arc<Mutex<State>>,
+    storage: &Arc<Storage>,
+    outgoing_tx: Sender<(PublicKey, PublicKey, Sender<Result<()>>)>,
+) -> Result<()> {
+    let mut retries = HashMap::<PeerHostPair, Retry>::new();
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    let (done_tx, mut done_rx) = channel(64);
+
+    loop {
+        interval.tick().await;
+
+        while let Ok((pair, connected)) = done_rx.try_recv() {
+            if let Some(retry) = retries.get_mut(&pair) {
+                retry.attempted(Instant::now(), connected);
+            }
+        }
+
+        let storage = storage.clone();
+
+        let pairs = match spawn_blocking(move || storage.outbox_pairs()).await {
+            Ok(Ok(n)) => n,
+            e => {
+                println!("Error reading outbox: {:?}", e);
+                continue;
+            }
+        };
+
+        let mut waiting = Vec::new();
+
+        {
+            let state = state.lock().await;
+
+            for pair in pairs {
+                // Entries of addresses removed from the config wait until they are added back
+                let offline = state
+                    .addresses
+                    .get(&pair.host_public_key)
+                    .is_some_and(|x| !x.connected_peers.contains_key(&pair.peer_public_key));
+
+                if offline {
+                    waiting.push(pair);
+                }
+            }
+        }
+
+        retries.retain(|pair, retry| retry.dialing || waiting.contains(pair));
+
+        for pair in waiting {
+            let retry = retries.entry(pair.clone()).or_default();
+
+            if !retry.due(Instant::now()) {
+                continue;
+            }
+
+            let (tx, mut rx) = channel(1);
+
+            outgoing_tx
+                .send((pair.peer_public_key, pair.host_public_key, tx))
+                .await
+                .map_err(|_| BlackedoutError::Unexpected)?;
+
+            retry.dialing = true;
+
+            let done_tx = done_tx.clone();
+
+            tokio::spawn(async move {
+                let connected = matches!(rx.recv().await, Some(Ok(())));
+                done_tx.send((pair, connected)).await.ok();
+            });
+        }
+    }
+}
+
+/// Exponential backoff between dials of a peer
+#[derive(Default)]
+struct Retry {
+    next: Option<Instant>,
+    delay: Duration,
+    /// Set while a dial is in progress so the peer isn't dialed twice at once
+    dialing: bool,
+}
+
+impl Retry {
+    fn due(&self, now: Instant) -> bool {
+        !self.dialing && self.next.is_none_or(|next| now >= next)
+    }
+
+    /// A connection that drops right away is dialed again after the minimum delay
+    fn attempted(&mut self, now: Instant, connected: bool) {
+        self.delay = match connected {
+            true => RETRY_MIN,
+            false => (self.delay * 2).clamp(RETRY_MIN, RETRY_MAX),
+        };
+        self.next = Some(now + self.delay);
+        self.dialing = false;
+    }
+}
+
+#[test]
+fn dial_backoff() {
+    let start = Instant::now();
+    let mut retry = Retry::default();
+
+    assert!(retry.due(start));
+
+    retry.dialing = true;
+    assert!(!retry.due(start));
+
+    let delays = (0..9)
+        .map(|_| {
+            retry.attempted(start, false);
+            retry.delay.as_secs()
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(delays, [5, 10, 20, 40, 80, 160, 320, 600, 600]);
+    assert!(!retry.due(start + Duration::from_secs(599)));
+    assert!(retry.due(start + Duration::from_secs(600)));
+
+    retry.attempted(start, true);
+    assert_eq!(retry.delay, RETRY_MIN);
+}
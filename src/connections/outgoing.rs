@@ -12,7 +12,9 @@ use tokio::{
 use tokio_socks::tcp::Socks5Stream;
 
 use crate::{
+    client::model::PeerHostPair,
     config::Config,
+    crypto::{auth_payload, decapsulate_token, encapsulate_token},
     error::{BlackedoutError, Result},
     secure::SecureStream,
     state::State,
@@ -23,55 +25,71 @@ use crate::{
 use super::model::{Authenticate, BlackPacket};
 
 pub async fn start_outgoing(
-    config: &Config,
+    _config: &Config,
     state: &Arc<Mutex<State>>,
     storage: &Arc<Storage>,
     mut rx: Receiver<(PublicKey, PublicKey, Sender<Result<()>>)>,
 ) -> Result<()> {
+    // Dials can take minutes through Tor so each runs on its own
     while let Some((peer_public_key, host_public_key, reply)) = rx.recv().await {
-        reply
-            .send(handle_request(config, state, storage, peer_public_key, host_public_key).await)
-            .await
-            .ok();
+        let state = state.clone();
+        let storage = storage.clone();
+
+        tokio::spawn(async move {
+            reply
+                .send(handle_request(&state, &storage, peer_public_key, host_public_key).await)
+                .await
+                .ok();
+        });
     }
 
     Ok(())
 }
 
 async fn handle_request(
-    _config: &Config,
     state: &Arc<Mutex<State>>,
     storage: &Arc<Storage>,
     peer_public_key: PublicKey,
     host_public_key: PublicKey,
 ) -> Result<()> {
     let target_addr = format!("{}:21761", peer_public_key.to_onion_address());
-    let host_secret_key = ExpandedSecretKey::from_bytes(
-        &state
-            .lock()
-            .await
-            .addresses
-            .get(&host_public_key)
-            .unwrap()
-            .onion
-            .secret_key
-            .to_bytes(),
-    )
-    .unwrap();
+    let (host_secret_key, host_kyber_public_key, host_kyber_secret_key, config) = {
+        let state = state.lock().await;
+        let onion = &state.addresses.get(&host_public_key).unwrap().onion;
+
+        (
+            ExpandedSecretKey::from_bytes(&onion.secret_key.to_bytes()).unwrap(),
+            onion.kyber_public_key.clone(),
+            onion.kyber_secret_key.clone(),
+            state.connections.clone(),
+        )
+    };
 
     let mut stream = UnixStream::connect(PathBuf::new().join("data").join("tor.sock"))
         .map_err(Into::into)
         .and_then(|socket| Socks5Stream::connect_with_socket(socket, target_addr))
         .map_err(Into::into)
-        .and_then(|stream| SecureStream::new(stream, false))
+        .and_then(|stream| SecureStream::new(stream, false, &config))
         .await?;
 
-    let token = match stream
+    let features = super::hello(&mut stream).await?;
+
+    stream
+        .send(BlackPacket::Authenticate(Authenticate::Identity {
+            pub_key: host_public_key,
+            kyber_public_key: host_kyber_public_key.clone(),
+        }))
+        .await?;
+
+    let (ciphertext, kyber_public_key) = match stream
         .next()
         .await
         .ok_or(BlackedoutError::ConnectionClosed)??
     {
-        BlackPacket::Authenticate(Authenticate::Token(n)) => n,
+        BlackPacket::Authenticate(Authenticate::Token {
+            ciphertext,
+            kyber_public_key,
+        }) => (ciphertext, kyber_public_key),
         _ => {
             return Err(BlackedoutError::WrongPacketType(
                 "Expected an Authenticate::Token packet".to_string(),
@@ -79,14 +97,61 @@ async fn handle_request(
         }
     };
 
-    let signature = host_public_key.sign(&token, &host_secret_key);
+    let transcript = stream.transcript();
+    let token = decapsulate_token(&ciphertext, &host_kyber_secret_key)?;
+    let signature = host_public_key.sign(
+        &auth_payload(
+            true,
+            &transcript,
+            &host_kyber_public_key,
+            &ciphertext,
+            &token,
+        ),
+        &host_secret_key,
+    );
+
+    // Only the holder of the key the peer presented can get the challenge back out
+    let (challenge, challenge_ciphertext) = encapsulate_token(&kyber_public_key)?;
 
     stream
-        .send(BlackPacket::Authenticate(Authenticate::OnionAndSig {
-            pub_key: host_public_key,
+        .send(BlackPacket::Authenticate(Authenticate::Signature {
+            transcript,
             sig: signature.to_bytes(),
         }))
         .await?;
+    stream
+        .send(BlackPacket::Authenticate(Authenticate::Challenge {
+            ciphertext: challenge_ciphertext.clone(),
+        }))
+        .await?;
+
+    let packet = stream
+        .next()
+        .await
+        .ok_or(BlackedoutError::ConnectionClosed)??;
+
+    // The peer proves it holds the onion key we dialed and the identity key it presented
+    // before either is trusted
+    super::verify_sign(
+        packet,
+        &peer_public_key,
+        &transcript,
+        &auth_payload(
+            false,
+            &transcript,
+            &kyber_public_key,
+            &challenge_ciphertext,
+            &challenge,
+        ),
+    )?;
+
+    let pair = PeerHostPair {
+        peer_public_key,
+        host_public_key,
+    };
+
+    super::check_identity_key(storage, &pair, &kyber_public_key).await?;
+    super::pin_identity_key(storage, &pair, kyber_public_key).await?;
 
     super::connection_loop(
         state.clone(),
@@ -94,6 +159,7 @@ async fn handle_request(
         stream,
         peer_public_key,
         host_public_key,
+        features,
     )
     .await;
 
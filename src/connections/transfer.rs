@@ -0,0 +1,712 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use tokio::{
+    sync::{mpsc::Sender, Mutex},
+    task::spawn_blocking,
+};
+
+use crate::{
+    client::model::{ClientPacket, PeerHostPair},
+    error::{BlackedoutError, Result},
+    state::State,
+    storage::{model::Direction, Storage},
+    types::MessageId,
+};
+
+use super::{
+    model::{FileOffer, Transfer},
+    ToPeer,
+};
+
+/// Small enough that chat traffic sharing the connection only waits for a chunk or two
+const CHUNK_SIZE: u64 = 64 * 1024;
+/// Offers of a peer waiting for a client to accept or reject them. Further ones are ignored.
+const MAX_INCOMING_OFFERS: usize = 32;
+
+/// Transfers in progress. Received files are written to `<transfer ID>.part` in `directory` as
+/// they arrive, which is what makes them resumable even after a restart. The offer a client
+/// accepted is kept next to it in `<transfer ID>.offer`. Files being sent are offered again
+/// whenever the peer connects until it completes or rejects them. Only files in `shared` can be
+/// sent.
+pub struct Transfers {
+    pub directory: PathBuf,
+    pub shared: PathBuf,
+    pub outgoing: HashMap<MessageId, Outgoing>,
+    pub incoming: HashMap<MessageId, Incoming>,
+}
+
+pub struct Outgoing {
+    pub pair: PeerHostPair,
+    pub path: PathBuf,
+    pub offer: FileOffer,
+}
+
+pub struct Incoming {
+    pub pair: PeerHostPair,
+    pub offer: FileOffer,
+    /// Set once a client accepted the offer. Chunks are ignored until then.
+    pub accepted: bool,
+}
+
+/// What a client accepted, so only the same file from the same peer resumes after a restart
+#[derive(Deserialize, Serialize, PartialEq, Eq)]
+struct Accepted {
+    pair: PeerHostPair,
+    offer: FileOffer,
+}
+
+impl Transfers {
+    pub fn new(directory: PathBuf, shared: PathBuf) -> Self {
+        Transfers {
+            directory,
+            shared,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+        }
+    }
+
+    /// Files still to be sent to a peer
+    pub fn offers(&self, pair: &PeerHostPair) -> Vec<FileOffer> {
+        self.outgoing
+            .values()
+            .filter(|x| x.pair == *pair)
+            .map(|x| x.offer.clone())
+            .collect()
+    }
+
+    fn partial(&self, transfer_id: &MessageId) -> PathBuf {
+        self.file(transfer_id, "part")
+    }
+
+    fn accepted(&self, transfer_id: &MessageId) -> PathBuf {
+        self.file(transfer_id, "offer")
+    }
+
+    fn file(&self, transfer_id: &MessageId, extension: &str) -> PathBuf {
+        self.directory.join(format!(
+            "{}.{}",
+            data_encoding::HEXLOWER.encode(transfer_id.as_bytes()),
+            extension
+        ))
+    }
+
+    /// Whether a client accepted exactly this offer before a restart. What was received of
+    /// anything else under the same ID is dropped and a client has to accept it again.
+    fn resumable(&self, pair: &PeerHostPair, offer: &FileOffer) -> bool {
+        let accepted = Accepted {
+            pair: pair.clone(),
+            offer: offer.clone(),
+        };
+
+        let resumable = fs::read(self.accepted(&offer.transfer_id))
+            .ok()
+            .and_then(|x| bson::from_slice::<Accepted>(&x).ok())
+            .is_some_and(|x| x == accepted);
+
+        if !resumable {
+            self.remove(&offer.transfer_id);
+        }
+
+        resumable
+    }
+
+    /// Drops what is on disk of an incoming transfer
+    fn remove(&self, transfer_id: &MessageId) {
+        fs::remove_file(self.partial(transfer_id)).ok();
+        fs::remove_file(self.accepted(transfer_id)).ok();
+    }
+
+    fn incoming(&mut self, pair: &PeerHostPair, transfer_id: &MessageId) -> Result<&mut Incoming> {
+        self.incoming
+            .get_mut(transfer_id)
+            .filter(|x| x.pair == *pair)
+            .ok_or(BlackedoutError::TransferDoesNotExist)
+    }
+}
+
+/// Offers a file to a peer on behalf of a client. Blocks on reading the whole file for its
+/// hash, so call this from a blocking task.
+pub fn offer(path: &Path, mime: Option<String>) -> Result<FileOffer> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha3_256::new();
+    let size = std::io::copy(&mut file, &mut hasher)?;
+
+    Ok(FileOffer {
+        transfer_id: MessageId::random(),
+        name: path
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        size,
+        hash: hasher.finalize().into(),
+        mime: mime.unwrap_or_else(|| "application/octet-stream".to_string()),
+    })
+}
+
+/// Accepts an offer on behalf of a client, resuming from what earlier attempts left on disk
+pub async fn accept(
+    state: &Mutex<State>,
+    pair: &PeerHostPair,
+    transfer_id: &MessageId,
+) -> Result<Option<(Sender<ToPeer>, Transfer)>> {
+    let mut state = state.lock().await;
+    let partial = state.transfers.partial(transfer_id);
+    let accepted = state.transfers.accepted(transfer_id);
+    let incoming = state.transfers.incoming(pair, transfer_id)?;
+
+    save_accepted(&accepted, incoming)?;
+    incoming.accepted = true;
+
+    let accept = Transfer::Accept {
+        transfer_id: *transfer_id,
+        offset: partial_length(&partial),
+    };
+
+    Ok(peer(&state, pair).map(|x| (x, accept)))
+}
+
+/// Rejects an offer on behalf of a client and drops what was received of it
+pub async fn reject(
+    state: &Mutex<State>,
+    pair: &PeerHostPair,
+    transfer_id: &MessageId,
+) -> Result<Option<(Sender<ToPeer>, Transfer)>> {
+    let mut state = state.lock().await;
+
+    state.transfers.incoming(pair, transfer_id)?;
+    state.transfers.incoming.remove(transfer_id);
+    state.transfers.remove(transfer_id);
+
+    let reject = Transfer::Reject {
+        transfer_id: *transfer_id,
+    };
+
+    Ok(peer(&state, pair).map(|x| (x, reject)))
+}
+
+/// Handles a transfer packet from the peer of a connection. Returns the reply, if any.
+pub async fn handle(
+    state: &Arc<Mutex<State>>,
+    storage: &Arc<Storage>,
+    pair: &PeerHostPair,
+    transfer: Transfer,
+) -> Option<Transfer> {
+    match transfer {
+        Transfer::Offer(offer) => {
+            let (accepted, offset, new) = {
+                let mut state = state.lock().await;
+                let transfers = &mut state.transfers;
+                let partial = transfers.partial(&offer.transfer_id);
+
+                let (accepted, new) = match transfers.incoming.get(&offer.transfer_id) {
+                    Some(x) if x.pair != *pair => return None,
+                    Some(x) => (x.accepted, false),
+                    // Accepted before a restart
+                    None => (transfers.resumable(pair, &offer), true),
+                };
+
+                let offers = transfers
+                    .incoming
+                    .values()
+                    .filter(|x| x.pair == *pair)
+                    .count();
+
+                if new && offers >= MAX_INCOMING_OFFERS {
+                    println!("Ignoring file offer, the peer has too many pending");
+                    return None;
+                }
+
+                if new {
+                    transfers.incoming.insert(
+                        offer.transfer_id,
+                        Incoming {
+                            pair: pair.clone(),
+                            offer: offer.clone(),
+                            accepted,
+                        },
+                    );
+                }
+
+                (accepted, partial_length(&partial), new)
+            };
+
+            if new && !accepted {
+                storage
+                    .broadcast(ClientPacket::FileOffered {
+                        pair: pair.clone(),
+                        direction: Direction::Incoming,
+                        offer: offer.clone(),
+                    })
+                    .await;
+            }
+
+            accepted.then_some(Transfer::Accept {
+                transfer_id: offer.transfer_id,
+                offset,
+            })
+        }
+        Transfer::Accept {
+            transfer_id,
+            offset,
+        } => {
+            let state = state.lock().await;
+
+            let outgoing = state
+                .transfers
+                .outgoing
+                .get(&transfer_id)
+                .filter(|x| x.pair == *pair && offset <= x.offer.size)?;
+
+            tokio::spawn(send_chunks(
+                peer(&state, pair)?,
+                storage.clone(),
+                pair.clone(),
+                outgoing.path.clone(),
+                outgoing.offer.clone(),
+                offset,
+            ));
+
+            None
+        }
+        Transfer::Reject { transfer_id } => {
+            remove_outgoing(state, pair, &transfer_id).await?;
+
+            storage
+                .broadcast(ClientPacket::FileRejected {
+                    pair: pair.clone(),
+                    transfer_id,
+                    direction: Direction::Outgoing,
+                })
+                .await;
+
+            None
+        }
+        Transfer::Chunk {
+            transfer_id,
+            offset,
+            data,
+        } => {
+            let (offer, partial, directory) = {
+                let mut state = state.lock().await;
+                let partial = state.transfers.partial(&transfer_id);
+                let directory = state.transfers.directory.clone();
+                let incoming = state
+                    .transfers
+                    .incoming(pair, &transfer_id)
+                    .ok()
+                    .filter(|x| x.accepted)?;
+
+                (incoming.offer.clone(), partial, directory)
+            };
+
+            let size = offer.size;
+            let written = {
+                let partial = partial.clone();
+                spawn_blocking(move || write_chunk(&partial, offset, &data, size)).await
+            };
+
+            let length = match written {
+                Ok(Ok(n)) => n,
+                e => {
+                    println!("Error writing file chunk: {:?}", e);
+                    return None;
+                }
+            };
+
+            storage
+                .broadcast(ClientPacket::FileProgress {
+                    pair: pair.clone(),
+                    transfer_id,
+                    direction: Direction::Incoming,
+                    offset: length,
+                    size,
+                })
+                .await;
+
+            if length < size {
+                return None;
+            }
+
+            // Chunks and offers are ignored while the file is verified
+            if let Ok(incoming) = state.lock().await.transfers.incoming(pair, &transfer_id) {
+                incoming.accepted = false;
+            }
+
+            // Hashing a large file takes a while, the connection goes on meanwhile
+            tokio::spawn(complete(
+                state.clone(),
+                storage.clone(),
+                pair.clone(),
+                partial,
+                directory,
+                offer,
+            ));
+
+            None
+        }
+        Transfer::Complete {
+            transfer_id,
+            verified,
+        } => {
+            remove_outgoing(state, pair, &transfer_id).await?;
+
+            storage
+                .broadcast(ClientPacket::FileCompleted {
+                    pair: pair.clone(),
+                    transfer_id,
+                    direction: Direction::Outgoing,
+                    verified,
+                    path: None,
+                })
+                .await;
+
+            None
+        }
+    }
+}
+
+/// Sends a file from `offset` on, through the connection's channel so chat data is sent in
+/// between chunks. Stops when the connection closes, the peer resumes on the next one.
+async fn send_chunks(
+    peer: Sender<ToPeer>,
+    storage: Arc<Storage>,
+    pair: PeerHostPair,
+    path: PathBuf,
+    offer: FileOffer,
+    mut offset: u64,
+) {
+    let mut file = match File::open(&path) {
+        Ok(n) => n,
+        Err(e) => {
+            println!("Error opening file to send: {:?}", e);
+            return;
+        }
+    };
+
+    // Even an empty file gets a chunk so the receiver gets to finish it
+    loop {
+        let length = CHUNK_SIZE.min(offer.size - offset);
+
+        let read = spawn_blocking(move || {
+            let mut data = vec![0; length as usize];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut data)?;
+
+            Ok::<_, BlackedoutError>((file, data))
+        })
+        .await;
+
+        let data = match read {
+            Ok(Ok((n, data))) => {
+                file = n;
+                data
+            }
+            e => {
+                println!("Error reading file to send: {:?}", e);
+                return;
+            }
+        };
+
+        if peer
+            .send(ToPeer::Transfer(Transfer::Chunk {
+                transfer_id: offer.transfer_id,
+                offset,
+                data,
+            }))
+            .await
+            .is_err()
+        {
+            return;
+        }
+
+        offset += length;
+
+        storage
+            .broadcast(ClientPacket::FileProgress {
+                pair: pair.clone(),
+                transfer_id: offer.transfer_id,
+                direction: Direction::Outgoing,
+                offset,
+                size: offer.size,
+            })
+            .await;
+
+        if offset == offer.size {
+            return;
+        }
+    }
+}
+
+/// Verifies and moves a received file, then tells the peer and clients how it went
+async fn complete(
+    state: Arc<Mutex<State>>,
+    storage: Arc<Storage>,
+    pair: PeerHostPair,
+    partial: PathBuf,
+    directory: PathBuf,
+    offer: FileOffer,
+) {
+    let transfer_id = offer.transfer_id;
+
+    let path = match spawn_blocking(move || finish(&partial, &directory, &offer)).await {
+        Ok(Ok(n)) => n,
+        e => {
+            println!("Error finishing file transfer: {:?}", e);
+            return;
+        }
+    };
+
+    let peer = {
+        let mut state = state.lock().await;
+        state.transfers.incoming.remove(&transfer_id);
+        fs::remove_file(state.transfers.accepted(&transfer_id)).ok();
+        peer(&state, &pair)
+    };
+
+    let verified = path.is_some();
+
+    storage
+        .broadcast(ClientPacket::FileCompleted {
+            pair: pair.clone(),
+            transfer_id,
+            direction: Direction::Incoming,
+            verified,
+            path,
+        })
+        .await;
+
+    // Otherwise the peer offers the file again and a client decides anew
+    if let Some(peer) = peer {
+        peer.send(ToPeer::Transfer(Transfer::Complete {
+            transfer_id,
+            verified,
+        }))
+        .await
+        .ok();
+    }
+}
+
+/// Only the peer a file was offered to can end its transfer
+async fn remove_outgoing(
+    state: &Mutex<State>,
+    pair: &PeerHostPair,
+    transfer_id: &MessageId,
+) -> Option<Outgoing> {
+    let mut state = state.lock().await;
+
+    state
+        .transfers
+        .outgoing
+        .get(transfer_id)
+        .filter(|x| x.pair == *pair)?;
+    state.transfers.outgoing.remove(transfer_id)
+}
+
+/// The channel to the connection of a peer
+fn peer(state: &State, pair: &PeerHostPair) -> Option<Sender<ToPeer>> {
+    state
+        .addresses
+        .get(&pair.host_public_key)?
+        .connected_peers
+        .get(&pair.peer_public_key)
+        .cloned()
+}
+
+fn partial_length(partial: &Path) -> u64 {
+    fs::metadata(partial).map_or(0, |x| x.len())
+}
+
+/// Keeps what a client accepted next to the partial file
+fn save_accepted(path: &Path, incoming: &Incoming) -> Result<()> {
+    let accepted = Accepted {
+        pair: incoming.pair.clone(),
+        offer: incoming.offer.clone(),
+    };
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, bson::to_vec(&accepted)?)?;
+
+    Ok(())
+}
+
+/// Appends a chunk to the partial file. Returns its new length.
+fn write_chunk(partial: &Path, offset: u64, data: &[u8], size: u64) -> Result<u64> {
+    if let Some(parent) = partial.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(partial)?;
+    let length = file.metadata()?.len();
+
+    if offset != length || length + data.len() as u64 > size {
+        return Err(BlackedoutError::UnexpectedChunk);
+    }
+
+    file.write_all(data)?;
+
+    Ok(length + data.len() as u64)
+}
+
+/// Moves a complete file into the download directory if its hash matches, otherwise deletes
+/// it. Returns where the file went.
+fn finish(partial: &Path, directory: &Path, offer: &FileOffer) -> Result<Option<PathBuf>> {
+    let mut file = File::open(partial)?;
+    file.sync_data()?;
+
+    let mut hasher = Sha3_256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+
+    if hasher.finalize()[..] != offer.hash {
+        fs::remove_file(partial)?;
+        return Ok(None);
+    }
+
+    let path = destination(directory, &offer.name);
+    fs::rename(partial, &path)?;
+
+    Ok(Some(path))
+}
+
+/// A path in the download directory for a name chosen by the peer. Only the file name is kept
+/// and existing files are never replaced.
+fn destination(directory: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name)
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .filter(|x| !x.starts_with('.'))
+        .unwrap_or_else(|| "file".to_string());
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) => (stem.to_string(), format!(".{}", extension)),
+        None => (name.clone(), String::new()),
+    };
+
+    (1..)
+        .map(|i| match i {
+            1 => directory.join(&name),
+            i => directory.join(format!("{} ({}){}", stem, i, extension)),
+        })
+        .find(|x| !x.exists())
+        .unwrap()
+}
+
+#[test]
+fn resumable_files() {
+    let directory = std::env::temp_dir().join(format!("blackedout-{}", rand::random::<u64>()));
+    let source = directory.join("notes.txt");
+    let data = vec![7; CHUNK_SIZE as usize + 10];
+
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(&source, &data).unwrap();
+
+    let mut offer = offer(&source, None).unwrap();
+    let partial = Transfers::new(directory.clone(), directory.clone()).partial(&offer.transfer_id);
+    let (first, second) = data.split_at(CHUNK_SIZE as usize);
+
+    assert_eq!(offer.name, "notes.txt");
+    assert_eq!(offer.size, data.len() as u64);
+    assert_eq!(
+        write_chunk(&partial, 0, first, offer.size).unwrap(),
+        CHUNK_SIZE
+    );
+
+    // Interrupted, the peer is asked to resume from what is on disk
+    assert_eq!(partial_length(&partial), CHUNK_SIZE);
+    assert!(matches!(
+        write_chunk(&partial, 0, first, offer.size),
+        Err(BlackedoutError::UnexpectedChunk)
+    ));
+    assert!(matches!(
+        write_chunk(&partial, CHUNK_SIZE, &data, offer.size),
+        Err(BlackedoutError::UnexpectedChunk)
+    ));
+    assert_eq!(
+        write_chunk(&partial, CHUNK_SIZE, second, offer.size).unwrap(),
+        offer.size
+    );
+
+    let path = finish(&partial, &directory, &offer).unwrap().unwrap();
+
+    assert_eq!(path, directory.join("notes (2).txt"));
+    assert_eq!(fs::read(&path).unwrap(), data);
+    assert!(!partial.exists());
+
+    offer.hash = [0; 32];
+    write_chunk(&partial, 0, &data, offer.size).unwrap();
+
+    assert_eq!(finish(&partial, &directory, &offer).unwrap(), None);
+    assert!(!partial.exists());
+
+    assert_eq!(
+        destination(&directory, "../.bashrc"),
+        directory.join("file")
+    );
+    assert_eq!(destination(&directory, "/tmp/a"), directory.join("a"));
+
+    fs::remove_dir_all(directory).ok();
+}
+
+#[test]
+fn resume_accepted_offers() {
+    use crate::types::PublicKey;
+
+    let directory = std::env::temp_dir().join(format!("blackedout-{}", rand::random::<u64>()));
+    let pair = PeerHostPair {
+        peer_public_key: PublicKey::from_onion_address(
+            "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion",
+        )
+        .unwrap(),
+        host_public_key: PublicKey::from_onion_address(
+            "sp3k262uwy4r2k3ycr5awluarykdpag6a7y33jxop4cs2lu5uz5sseqd.onion",
+        )
+        .unwrap(),
+    };
+
+    let transfers = Transfers::new(directory.clone(), directory.clone());
+    let offer = FileOffer {
+        transfer_id: MessageId::random(),
+        name: "notes.txt".to_string(),
+        size: 10,
+        hash: [1; 32],
+        mime: "text/plain".to_string(),
+    };
+    let partial = transfers.partial(&offer.transfer_id);
+    let incoming = Incoming {
+        pair: pair.clone(),
+        offer: offer.clone(),
+        accepted: true,
+    };
+
+    // Never accepted
+    assert!(!transfers.resumable(&pair, &offer));
+
+    save_accepted(&transfers.accepted(&offer.transfer_id), &incoming).unwrap();
+    write_chunk(&partial, 0, &[7; 4], offer.size).unwrap();
+
+    assert!(transfers.resumable(&pair, &offer));
+    assert!(partial.exists());
+
+    // The same ID offered for another file after a restart starts over
+    let other = FileOffer {
+        size: 11,
+        ..offer.clone()
+    };
+
+    assert!(!transfers.resumable(&pair, &other));
+    assert!(!partial.exists());
+    assert!(!transfers.accepted(&offer.transfer_id).exists());
+    assert!(!transfers.resumable(&pair, &offer));
+
+    fs::remove_dir_all(directory).ok();
+}
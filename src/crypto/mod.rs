@@ -1,48 +1,137 @@
+use std::{
+    mem,
+    ops::Deref,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
 use pqcrypto_kyber::kyber102490s;
-use pqcrypto_traits::kem::{Ciphertext, PublicKey, SharedSecret};
+use pqcrypto_traits::kem::{Ciphertext, PublicKey, SecretKey, SharedSecret};
 use sha3::{Digest, Sha3_256};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey};
+use zeroize::Zeroizing;
+
+use crate::error::{BlackedoutError, Result};
+
+/// Sent by both sides before the key exchange, followed by `HANDSHAKE_VERSION`
+const HANDSHAKE_MAGIC: &[u8; 10] = b"blackedout";
+/// Bumped whenever the key exchange changes, since a peer expecting another one would only
+/// see garbage. Unlike the protocol version this can't be negotiated later on.
+const HANDSHAKE_VERSION: u8 = 1;
 
-use crate::error::Result;
+/// Ephemeral X25519 exchange so the session key stays classically secure even if Kyber breaks
+macro_rules! x25519_a {
+    ($stream:ident, $secrets:ident, $transcript:ident) => {
+        let mut buf = [0u8; 32];
+        $stream.read_exact(&mut buf).await?;
+        $transcript.update(buf);
+
+        let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        let pk = X25519PublicKey::from(&secret);
+        $stream.write_all(pk.as_bytes()).await?;
+        $transcript.update(pk.as_bytes());
+
+        $secrets.push(x25519_secret(secret, buf)?);
+    };
+}
+
+macro_rules! x25519_b {
+    ($stream:ident, $secrets:ident, $transcript:ident) => {
+        let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        let pk = X25519PublicKey::from(&secret);
+
+        let mut buf = [0u8; 32];
+        $stream.write_all(pk.as_bytes()).await?;
+        $transcript.update(pk.as_bytes());
+        $stream.read_exact(&mut buf).await?;
+        $transcript.update(buf);
+
+        $secrets.push(x25519_secret(secret, buf)?);
+    };
+}
 
 macro_rules! handshake_a {
-    ($algorithm:ident, $stream:ident, $secrets:ident) => {
+    ($algorithm:ident, $stream:ident, $secrets:ident, $transcript:ident) => {
         let mut buf = [0u8; $algorithm::public_key_bytes()];
         $stream.read_exact(&mut buf).await?;
+        $transcript.update(buf);
 
         let pk = PublicKey::from_bytes(&buf)?;
         let (sk, ct) = $algorithm::encapsulate(&pk);
-        $secrets.push(sk.as_bytes().to_vec());
+        let sk = Wiped::new(sk);
+        $secrets.push(Zeroizing::new(sk.as_bytes().to_vec()));
         $stream.write_all(ct.as_bytes()).await?;
+        $transcript.update(ct.as_bytes());
     };
 }
 
 macro_rules! handshake_b {
-    ($algorithm:ident, $stream:ident, $secrets:ident) => {
+    ($algorithm:ident, $stream:ident, $secrets:ident, $transcript:ident) => {
         let (pk, sk) = $algorithm::keypair();
+        let sk = Wiped::new(sk);
 
         let mut buf = [0u8; $algorithm::ciphertext_bytes()];
         $stream.write_all(pk.as_bytes()).await?;
+        $transcript.update(pk.as_bytes());
         $stream.read_exact(&mut buf).await?;
+        $transcript.update(buf);
 
         let ct = Ciphertext::from_bytes(&buf)?;
-        let sk = $algorithm::decapsulate(&ct, &sk);
-        $secrets.push(sk.as_bytes().to_vec());
+        let sk = Wiped::new($algorithm::decapsulate(&ct, &sk));
+        $secrets.push(Zeroizing::new(sk.as_bytes().to_vec()));
     };
 }
 
+/// Returns the session key and a hash of everything both sides sent, which is the same on both
+/// ends of one channel and different on any other
 pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
     stream: &mut S,
     alice: bool,
-) -> Result<[u8; 32]> {
+) -> Result<([u8; 32], [u8; 32])> {
     let mut secrets = Vec::new();
+    let mut transcript = Sha3_256::new();
+
+    let mut prefix = [0u8; HANDSHAKE_MAGIC.len() + 1];
+    prefix[..HANDSHAKE_MAGIC.len()].copy_from_slice(HANDSHAKE_MAGIC);
+    prefix[HANDSHAKE_MAGIC.len()] = HANDSHAKE_VERSION;
+
+    // Nodes from before the prefix never send one, so they fail here or stall until Tor gives up
+    let mut peer = [0u8; HANDSHAKE_MAGIC.len() + 1];
+    stream.write_all(&prefix).await?;
+    stream.read_exact(&mut peer).await?;
+
+    if peer != prefix {
+        return Err(BlackedoutError::UnsupportedVersion);
+    }
+
+    transcript.update(prefix);
 
     if alice {
-        handshake_a!(kyber102490s, stream, secrets);
+        x25519_a!(stream, secrets, transcript);
+        handshake_a!(kyber102490s, stream, secrets, transcript);
     } else {
-        handshake_b!(kyber102490s, stream, secrets);
+        x25519_b!(stream, secrets, transcript);
+        handshake_b!(kyber102490s, stream, secrets, transcript);
     }
 
+    let secrets = secrets.iter().map(|x| &x[..]).collect::<Vec<_>>();
+
+    Ok((derive_key(&secrets), transcript.finalize().into()))
+}
+
+/// Fails for peer keys of low order, which would make the shared secret predictable
+fn x25519_secret(secret: EphemeralSecret, peer: [u8; 32]) -> Result<Zeroizing<Vec<u8>>> {
+    let shared = secret.diffie_hellman(&X25519PublicKey::from(peer));
+
+    match shared.was_contributory() {
+        true => Ok(Zeroizing::new(shared.as_bytes().to_vec())),
+        false => Err(BlackedoutError::BadPublicKey),
+    }
+}
+
+/// The session key is SHA3-256 of every shared secret in the order they were exchanged, so it
+/// is only as weak as the strongest of them
+fn derive_key(secrets: &[&[u8]]) -> [u8; 32] {
     let mut sha = Sha3_256::new();
 
     for secret in secrets.iter() {
@@ -51,5 +140,200 @@ pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
 
     let mut key = [0u8; 32];
     key.clone_from_slice(sha.finalize().as_slice());
-    Ok(key)
+    key
+}
+
+/// Overwrites a value with zeros when it is dropped, for key material in types that can't wipe
+/// themselves, like Kyber secrets and AES key schedules. Copies made by moving the value before
+/// it was wrapped are out of reach.
+pub struct Wiped<T>(T);
+
+impl<T> Wiped<T> {
+    /// Only takes values without drop glue, which are held inline and never used again once
+    /// zeroed
+    pub fn new(value: T) -> Self {
+        assert!(!mem::needs_drop::<T>());
+        Wiped(value)
+    }
+}
+
+impl<T> Deref for Wiped<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> Drop for Wiped<T> {
+    fn drop(&mut self) {
+        let bytes = &mut self.0 as *mut T as *mut u8;
+
+        for i in 0..mem::size_of::<T>() {
+            // SAFETY: Within the value, which `new` made sure has nothing to drop afterwards
+            unsafe { bytes.add(i).write_volatile(0) };
+        }
+
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+/// What either side signs to authenticate. Covers the token along with the handshake transcript
+/// and the messages that carried the token, so the signature is only good for the channel it was
+/// made on. The side is included so a signature can't be reflected back at its signer.
+pub fn auth_payload(
+    dialer: bool,
+    transcript: &[u8; 32],
+    identity_key: &[u8],
+    ciphertext: &[u8],
+    token: &[u8; 32],
+) -> [u8; 32] {
+    let mut sha = Sha3_256::new();
+    sha.update(match dialer {
+        true => &b"blackedout authentication dialer"[..],
+        false => &b"blackedout authentication listener"[..],
+    });
+    sha.update(transcript);
+    sha.update(Sha3_256::digest(identity_key));
+    sha.update(Sha3_256::digest(ciphertext));
+    sha.update(token);
+
+    sha.finalize().into()
+}
+
+/// Encapsulates a fresh authentication token to the identity key of a peer. Returns the token
+/// and the ciphertext only the holder of the secret key can get it back from.
+pub fn encapsulate_token(identity_key: &[u8]) -> Result<([u8; 32], Vec<u8>)> {
+    let pk = kyber102490s::PublicKey::from_bytes(identity_key)?;
+    let (ss, ct) = kyber102490s::encapsulate(&pk);
+
+    Ok((token_of(&ss), ct.as_bytes().to_vec()))
+}
+
+pub fn decapsulate_token(ciphertext: &[u8], secret_key: &[u8]) -> Result<[u8; 32]> {
+    let ct = kyber102490s::Ciphertext::from_bytes(ciphertext)?;
+    let sk = kyber102490s::SecretKey::from_bytes(secret_key)?;
+
+    Ok(token_of(&kyber102490s::decapsulate(&ct, &sk)))
+}
+
+fn token_of(ss: &kyber102490s::SharedSecret) -> [u8; 32] {
+    let mut sha = Sha3_256::new();
+    sha.update(b"blackedout token");
+    sha.update(ss.as_bytes());
+
+    sha.finalize().into()
+}
+
+#[test]
+fn hybrid_key_derivation() {
+    let hex = |x: &str| data_encoding::HEXLOWER.decode(x.as_bytes()).unwrap();
+    let array = |x: &str| <[u8; 32]>::try_from(hex(x)).unwrap();
+
+    // RFC 7748, section 6.1
+    let alice = array("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+    let bob = array("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+    let x25519 = x25519_dalek::x25519(alice, bob);
+
+    assert_eq!(
+        x25519[..],
+        hex("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742")
+    );
+
+    let kyber = (0..32).collect::<Vec<u8>>();
+
+    assert_eq!(
+        derive_key(&[&x25519[..], &kyber[..]])[..],
+        hex("a42644c75745d6b5d173b36cdda3cdd1ecb5e4cedd678733c947e88494f5f5f1")
+    );
+    assert_eq!(
+        derive_key(&[&kyber[..], &x25519[..]])[..],
+        hex("5abae8a98d7bfef6b2f4029b2bd64b09f60a5aa6392dcc4cd20f1827885300fc")
+    );
+}
+
+#[tokio::test]
+async fn hybrid_handshake() {
+    let (mut a, mut b) = tokio::io::duplex(4096);
+    let (a, b) = tokio::join!(handshake(&mut a, true), handshake(&mut b, false));
+    let (a, b) = (a.unwrap(), b.unwrap());
+
+    assert_eq!(a, b);
+
+    let (mut c, mut d) = tokio::io::duplex(4096);
+    let (c, _) = tokio::join!(handshake(&mut c, true), handshake(&mut d, false));
+
+    assert_ne!(a.1, c.unwrap().1);
+}
+
+#[test]
+fn auth_payload_binding() {
+    let payload = auth_payload(true, &[1; 32], &[2; 8], &[3; 8], &[4; 32]);
+
+    assert_eq!(
+        payload,
+        auth_payload(true, &[1; 32], &[2; 8], &[3; 8], &[4; 32])
+    );
+    assert_ne!(
+        payload,
+        auth_payload(true, &[9; 32], &[2; 8], &[3; 8], &[4; 32])
+    );
+    assert_ne!(
+        payload,
+        auth_payload(true, &[1; 32], &[9; 8], &[3; 8], &[4; 32])
+    );
+    assert_ne!(
+        payload,
+        auth_payload(true, &[1; 32], &[2; 8], &[9; 8], &[4; 32])
+    );
+    assert_ne!(
+        payload,
+        auth_payload(true, &[1; 32], &[2; 8], &[3; 8], &[9; 32])
+    );
+    assert_ne!(
+        payload,
+        auth_payload(false, &[1; 32], &[2; 8], &[3; 8], &[4; 32])
+    );
+}
+
+#[test]
+fn token_encapsulation() {
+    let (pk, sk) = kyber102490s::keypair();
+    let (token, ciphertext) = encapsulate_token(pk.as_bytes()).unwrap();
+
+    assert_eq!(
+        decapsulate_token(&ciphertext, sk.as_bytes()).unwrap(),
+        token
+    );
+
+    let (_, other) = kyber102490s::keypair();
+    assert_ne!(
+        decapsulate_token(&ciphertext, other.as_bytes()).unwrap(),
+        token
+    );
+    assert!(encapsulate_token(&pk.as_bytes()[1..]).is_err());
+}
+
+#[tokio::test]
+async fn handshake_version() {
+    let (mut a, mut b) = tokio::io::duplex(4096);
+
+    // What a node from before the prefix sends first, the start of its X25519 key
+    let old = [9u8; HANDSHAKE_MAGIC.len()];
+
+    let (a, _) = tokio::join!(handshake(&mut a, true), async {
+        b.write_all(&old).await.unwrap();
+        b.write_all(&[HANDSHAKE_VERSION]).await.unwrap();
+    });
+
+    assert!(matches!(a, Err(BlackedoutError::UnsupportedVersion)));
+
+    let (mut c, mut d) = tokio::io::duplex(4096);
+
+    let (c, _) = tokio::join!(handshake(&mut c, true), async {
+        d.write_all(HANDSHAKE_MAGIC).await.unwrap();
+        d.write_all(&[HANDSHAKE_VERSION + 1]).await.unwrap();
+    });
+
+    assert!(matches!(c, Err(BlackedoutError::UnsupportedVersion)));
 }
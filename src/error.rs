@@ -7,20 +7,48 @@ pub enum BlackedoutError {
     AesBadLength,
     AesBadTag,
     AesEncryptionError,
+    ArchiveTooNew,
     AxumError(axum::Error),
+    Argon2(argon2::Error),
+    BadArguments(String),
     BadHostname,
+    BadPassphrase,
     BadPublicKey,
     BadSecretKey,
     BadSignature,
     HostPublicKeyDoesNotExist,
+    IdentityKeyChanged,
+    InvalidReaction,
+    Json(serde_json::Error),
+    MessageDoesNotExist,
+    NotAnArchive,
+    NoStorageBackend,
+    NotMessageSender,
     PeerPublicKeyDoesNotExist,
+    MissingPassphrase,
+    Migration(diesel_migrations::RunMigrationsError),
     Hyper(hyper::Error),
     SocksError(tokio_socks::Error),
     SignatureVerificationFailed,
+    StorageEncrypted,
+    StorageTooNew,
+    TransferDoesNotExist,
     Base32Error(data_encoding::DecodeError),
     BsonError(bson::de::Error),
+    BsonSerError(bson::ser::Error),
     ConnectionClosed,
+    ContactDoesNotExist,
+    CorruptedRecord,
+    FrameTooLarge,
+    PingTimeout,
+    RateLimited,
+    UnexpectedChunk,
+    UnexpectedRekey,
+    UnsupportedVersion,
+    Diesel(diesel::result::Error),
+    DieselConnection(diesel::ConnectionError),
     TorShutdown(Box<BlackedoutError>),
+    TranscriptMismatch,
     Io(std::io::Error),
     PqCrypto(pqcrypto_traits::Error),
     WrongPacketType(String),
@@ -51,10 +79,16 @@ macro_rules! impl_from {
     };
 }
 
+impl_from!(argon2::Error, Argon2);
 impl_from!(axum::Error, AxumError);
 impl_from!(bson::de::Error, BsonError);
+impl_from!(bson::ser::Error, BsonSerError);
 impl_from!(data_encoding::DecodeError, Base32Error);
+impl_from!(diesel::result::Error, Diesel);
+impl_from!(diesel::ConnectionError, DieselConnection);
+impl_from!(diesel_migrations::RunMigrationsError, Migration);
 impl_from!(hyper::Error, Hyper);
 impl_from!(tokio_socks::Error, SocksError);
 impl_from!(std::io::Error, Io);
 impl_from!(pqcrypto_traits::Error, PqCrypto);
+impl_from!(serde_json::Error, Json);
@@ -1,3 +1,7 @@
+#[macro_use]
+extern crate diesel;
+
+mod cli;
 mod client;
 mod config;
 mod connections;
@@ -12,35 +16,51 @@ mod types;
 
 use std::sync::Arc;
 
-use futures::future::try_join4;
+use futures::future::try_join5;
 use tokio::sync::{mpsc::channel, Mutex};
 
+use crate::cli::{Command, USAGE};
 use crate::config::Config;
-use crate::connections::{incoming, outgoing};
+use crate::connections::{incoming, outbox, outgoing};
 use crate::state::State;
 use crate::storage::Storage;
 
 #[tokio::main]
 async fn main() {
+    let command = Command::parse(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{:?}\n\n{}", e, USAGE);
+        std::process::exit(2);
+    });
+
     let config = Config::load();
 
+    // Opened before Tor is spawned so a bad passphrase doesn't leave Tor running
+    let storage = Arc::new(Storage::new(&config).expect("Failed to initialize storage"));
+
+    if let Some(command) = command {
+        command.run(&storage).expect("Command failed");
+        return;
+    }
+
     println!("Waiting for Tor to start");
 
     let control = tor::spawn_tor(&config).expect("Failed to spawn Tor process");
-
-    let storage = Arc::new(Storage::new(&config));
     let state = Arc::new(Mutex::new(
         State::new(&config).expect("Failed to initialize state"),
     ));
 
+    // State read every hostname, so address policies can be resolved
+    storage.start_retention(&config);
+
     let (outgoing_tx, outgoing_rx) = channel(1);
 
     let a = tor::handle_tor(control);
     let b = incoming::start_incoming(&config, &state, &storage);
     let c = outgoing::start_outgoing(&config, &state, &storage, outgoing_rx);
-    let d = client::start_clients(&config, &storage, &state, outgoing_tx);
+    let d = client::start_clients(&config, &storage, &state, outgoing_tx.clone());
+    let e = outbox::start_dialer(&state, &storage, outgoing_tx);
 
     println!("Running asynchronous loop");
 
-    if let Err(_e) = try_join4(a, b, c, d).await {}
+    if let Err(_e) = try_join5(a, b, c, d, e).await {}
 }
@@ -1,6 +1,13 @@
 use std::{
+    collections::VecDeque,
+    io::ErrorKind,
     pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use aes_gcm::{
@@ -10,29 +17,189 @@ use aes_gcm::{
 
 use bytes::Bytes;
 use futures::{ready, Sink, Stream};
+use pqcrypto_kyber::kyber102490s;
+use pqcrypto_traits::kem::{Ciphertext, PublicKey, SharedSecret};
 use rand::RngCore;
+use sha3::{Digest, Sha3_256};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio_util::codec::{Framed, LengthDelimitedCodec, LengthDelimitedCodecError};
+use zeroize::Zeroizing;
 
-use crate::connections::model::BlackPacket;
-use crate::crypto::handshake;
+use crate::config::Connections;
+use crate::connections::model::{BlackPacket, Rekey};
+use crate::crypto::{handshake, Wiped};
 use crate::error::{BlackedoutError, Result};
 
+/// Rekeys of one direction are at least this far apart. A peer asking for them at twice the rate
+/// or more is cut off, since they never reach the rate limit of the connection.
+const REKEY_MIN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Each direction has its own key, which the sending side replaces with a fresh Kyber exchange
+/// every `rekey_messages` packets or `rekey_minutes`. Rekeys of the two directions are
+/// independent so they can cross in flight.
 pub struct SecureStream<S: AsyncRead + AsyncWrite + Unpin> {
     inner: Framed<S, LengthDelimitedCodec>,
-    cipher: Aes256Gcm,
+    send: Key,
+    receive: Key,
+    /// Encrypted frames that have to go out before anything else is sent
+    queued: VecDeque<Bytes>,
+    unflushed: bool,
+    rekeying: bool,
+    rekey_messages: u64,
+    rekey_after: Duration,
+    rekey_min_interval: Duration,
+    /// Packets sent with the current sending key and when it was set up
+    sent: u64,
+    keyed_at: Instant,
+    /// Kept while a rekey of the sending direction waits for the peer's ciphertext
+    rekey_secret: Option<Wiped<kyber102490s::SecretKey>>,
+    /// The receiving key the peer switches to with `Rekey::Switch`
+    next_receive: Option<Key>,
+    /// When the peer last asked for a rekey
+    peer_rekeyed_at: Option<Instant>,
+    received: Arc<AtomicU64>,
+    transcript: [u8; 32],
 }
 
 impl<S> SecureStream<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    pub async fn new(mut inner: S, alice: bool) -> Result<Self> {
-        let key = handshake(&mut inner, alice).await?;
-        let cipher = Aes256Gcm::new_from_slice(&key).unwrap();
-        let inner = Framed::new(inner, LengthDelimitedCodec::new());
+    /// Frames longer than `max_frame_size` from the other side end the stream with
+    /// `FrameTooLarge`
+    pub async fn new(mut inner: S, alice: bool, config: &Connections) -> Result<Self> {
+        let (key, transcript) = handshake(&mut inner, alice).await?;
+        let key = Zeroizing::new(key);
+        let inner = Framed::new(
+            inner,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(config.max_frame_size)
+                .new_codec(),
+        );
+
+        let alice_key = Key::new(derive(b"blackedout alice", &[&key[..]]));
+        let bob_key = Key::new(derive(b"blackedout bob", &[&key[..]]));
+        let (send, receive) = match alice {
+            true => (alice_key, bob_key),
+            false => (bob_key, alice_key),
+        };
+
+        Ok(SecureStream {
+            inner,
+            send,
+            receive,
+            queued: VecDeque::new(),
+            unflushed: false,
+            rekeying: false,
+            rekey_messages: config.rekey_messages.max(1),
+            rekey_after: Duration::from_secs(config.rekey_minutes.max(1) * 60),
+            rekey_min_interval: REKEY_MIN_INTERVAL,
+            sent: 0,
+            keyed_at: Instant::now(),
+            rekey_secret: None,
+            next_receive: None,
+            peer_rekeyed_at: None,
+            received: Default::default(),
+            transcript,
+        })
+    }
+
+    /// Hash of the key exchange, which authentication signatures are bound to
+    pub fn transcript(&self) -> [u8; 32] {
+        self.transcript
+    }
+
+    /// Bytes of frames read so far. Shared so it can still be read once the stream is split.
+    pub fn received(&self) -> Arc<AtomicU64> {
+        self.received.clone()
+    }
+
+    /// Starts rekeying the sending direction. Only called once the peer said it understands
+    /// `Rekey` packets, those from the peer are always handled.
+    pub fn enable_rekeying(&mut self) {
+        self.rekeying = true;
+    }
+
+    /// Sends queued frames. Ready once there are none left.
+    fn poll_queued(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while !self.queued.is_empty() {
+            ready!(Pin::new(&mut self.inner).poll_ready(cx))?;
+
+            let frame = self.queued.pop_front().unwrap();
+            Pin::new(&mut self.inner).start_send(frame)?;
+            self.unflushed = true;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Frames are sealed when they are queued, so queueing keeps them in order with respect to
+    /// key switches
+    fn queue(&mut self, packet: &BlackPacket) -> Result<()> {
+        let frame = self.send.seal(packet)?;
+        self.queued.push_back(frame);
+        Ok(())
+    }
+
+    fn handle_rekey(&mut self, rekey: Rekey) -> Result<()> {
+        match rekey {
+            Rekey::Request { public_key } if self.next_receive.is_none() => {
+                let now = Instant::now();
+
+                if self
+                    .peer_rekeyed_at
+                    .is_some_and(|x| now.duration_since(x) < self.rekey_min_interval / 2)
+                {
+                    return Err(BlackedoutError::RateLimited);
+                }
+
+                self.peer_rekeyed_at = Some(now);
+
+                let pk = kyber102490s::PublicKey::from_bytes(&public_key)?;
+                let (ss, ct) = kyber102490s::encapsulate(&pk);
+                let ss = Wiped::new(ss);
+
+                self.next_receive = Some(self.receive.next(ss.as_bytes()));
+                self.queue(&BlackPacket::Rekey(Rekey::Response {
+                    ciphertext: ct.as_bytes().to_vec(),
+                }))
+            }
+            Rekey::Response { ciphertext } => {
+                let sk = self
+                    .rekey_secret
+                    .take()
+                    .ok_or(BlackedoutError::UnexpectedRekey)?;
+                let ct = kyber102490s::Ciphertext::from_bytes(&ciphertext)?;
+                let ss = Wiped::new(kyber102490s::decapsulate(&ct, &sk));
+
+                // The peer has the new key, so the old one is sealed into a last frame and
+                // dropped
+                self.queue(&BlackPacket::Rekey(Rekey::Switch))?;
+                self.send = self.send.next(ss.as_bytes());
+                self.sent = 0;
+                self.keyed_at = Instant::now();
 
-        Ok(SecureStream { inner, cipher })
+                Ok(())
+            }
+            Rekey::Switch => {
+                self.receive = self
+                    .next_receive
+                    .take()
+                    .ok_or(BlackedoutError::UnexpectedRekey)?;
+
+                Ok(())
+            }
+            Rekey::Request { .. } => Err(BlackedoutError::UnexpectedRekey),
+        }
+    }
+
+    fn rekey_due(&self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.keyed_at);
+
+        self.rekeying
+            && self.rekey_secret.is_none()
+            && elapsed >= self.rekey_min_interval
+            && (self.sent >= self.rekey_messages || elapsed >= self.rekey_after)
     }
 }
 
@@ -43,34 +210,46 @@ where
     type Item = Result<BlackPacket>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut bytes = match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
-            Some(n) => match n {
-                Ok(n) => n,
-                Err(e) => return Poll::Ready(Some(Err(e.into()))),
-            },
-            None => return Poll::Ready(None),
-        };
+        loop {
+            // Rekey replies go out even while nothing else is being sent
+            if let Poll::Ready(Err(e)) = self.poll_queued(cx) {
+                return Poll::Ready(Some(Err(e)));
+            }
 
-        if bytes.len() < 28 {
-            return Poll::Ready(Some(Err(BlackedoutError::AesBadLength)));
-        }
+            if self.unflushed {
+                match Pin::new(&mut self.inner).poll_flush(cx) {
+                    Poll::Ready(Ok(_)) => self.unflushed = false,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                    Poll::Pending => {}
+                }
+            }
 
-        let (nonce, rest) = bytes.split_at_mut(12);
-        let (tag, buffer) = rest.split_at_mut(16);
+            let mut bytes = match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(n) => match n {
+                    Ok(n) => n,
+                    Err(e)
+                        if e.kind() == ErrorKind::InvalidData
+                            && e.get_ref()
+                                .is_some_and(|x| x.is::<LengthDelimitedCodecError>()) =>
+                    {
+                        return Poll::Ready(Some(Err(BlackedoutError::FrameTooLarge)));
+                    }
+                    Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                },
+                None => return Poll::Ready(None),
+            };
 
-        match self.cipher.decrypt_in_place_detached(
-            GenericArray::from_slice(nonce),
-            b"",
-            buffer,
-            GenericArray::from_slice(tag),
-        ) {
-            Ok(_) => {}
-            Err(_) => return Poll::Ready(Some(Err(BlackedoutError::AesBadTag))),
-        }
+            self.received
+                .fetch_add(bytes.len() as u64, Ordering::Relaxed);
 
-        match bson::from_slice::<BlackPacket>(buffer) {
-            Ok(n) => Poll::Ready(Some(Ok(n))),
-            Err(e) => Poll::Ready(Some(Err(e.into()))),
+            match self.receive.open(&mut bytes) {
+                Ok(BlackPacket::Rekey(rekey)) => {
+                    if let Err(e) = self.handle_rekey(rekey) {
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                }
+                n => return Poll::Ready(Some(n)),
+            }
         }
     }
 }
@@ -82,12 +261,70 @@ where
     type Error = BlackedoutError;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.poll_queued(cx))?;
         Pin::new(&mut self.inner).poll_ready(cx).map_err(Into::into)
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: BlackPacket) -> Result<()> {
+        let frame = self.send.seal(&item)?;
+
+        match self.queued.is_empty() {
+            true => Pin::new(&mut self.inner).start_send(frame)?,
+            false => self.queued.push_back(frame),
+        }
+
+        self.sent += 1;
+
+        if self.rekey_due(Instant::now()) {
+            let (pk, sk) = kyber102490s::keypair();
+
+            self.rekey_secret = Some(Wiped::new(sk));
+            self.queue(&BlackPacket::Rekey(Rekey::Request {
+                public_key: pk.as_bytes().to_vec(),
+            }))?;
+        }
+
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.poll_queued(cx))?;
+        ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+
+        self.unflushed = false;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.poll_queued(cx))?;
+        Pin::new(&mut self.inner).poll_close(cx).map_err(Into::into)
+    }
+}
+
+/// The key of one direction. The raw key and the cipher's key schedule are zeroed once it is
+/// replaced.
+struct Key {
+    raw: Zeroizing<[u8; 32]>,
+    cipher: Wiped<Aes256Gcm>,
+}
+
+impl Key {
+    fn new(raw: [u8; 32]) -> Self {
+        let raw = Zeroizing::new(raw);
+        let cipher = Wiped::new(Aes256Gcm::new_from_slice(&raw[..]).unwrap());
+
+        Key { raw, cipher }
+    }
+
+    /// The key after a rekey. Mixes the fresh secret with the current key so the new one is at
+    /// least as strong, while the old one can't be recovered from it.
+    fn next(&self, secret: &[u8]) -> Self {
+        Key::new(derive(b"blackedout rekey", &[&self.raw[..], secret]))
+    }
+
+    fn seal(&self, packet: &BlackPacket) -> Result<Bytes> {
         let mut ciphertext = vec![0u8; 28];
-        ciphertext.append(&mut bson::to_vec(&item).unwrap());
+        ciphertext.append(&mut bson::to_vec(packet).unwrap());
 
         let (nonce, rest) = ciphertext.split_at_mut(12);
         let (tag, buffer) = rest.split_at_mut(16);
@@ -101,16 +338,128 @@ where
                 .as_slice(),
         );
 
-        Pin::new(&mut self.inner)
-            .start_send(Bytes::from(ciphertext))
-            .map_err(Into::into)
+        Ok(Bytes::from(ciphertext))
     }
 
-    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        Pin::new(&mut self.inner).poll_flush(cx).map_err(Into::into)
+    fn open(&self, bytes: &mut [u8]) -> Result<BlackPacket> {
+        if bytes.len() < 28 {
+            return Err(BlackedoutError::AesBadLength);
+        }
+
+        let (nonce, rest) = bytes.split_at_mut(12);
+        let (tag, buffer) = rest.split_at_mut(16);
+
+        self.cipher
+            .decrypt_in_place_detached(
+                GenericArray::from_slice(nonce),
+                b"",
+                buffer,
+                GenericArray::from_slice(tag),
+            )
+            .map_err(|_| BlackedoutError::AesBadTag)?;
+
+        BlackPacket::decode(buffer)
     }
+}
 
-    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        Pin::new(&mut self.inner).poll_close(cx).map_err(Into::into)
+fn derive(label: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut sha = Sha3_256::new();
+    sha.update(label);
+
+    for part in parts {
+        sha.update(part);
     }
+
+    sha.finalize().into()
+}
+
+#[tokio::test]
+async fn rekeying() {
+    use futures::{SinkExt, StreamExt};
+
+    let config = Connections {
+        rekey_messages: 3,
+        ..Default::default()
+    };
+
+    let (a, b) = tokio::io::duplex(1 << 20);
+    let (a, b) = tokio::join!(
+        SecureStream::new(a, true, &config),
+        SecureStream::new(b, false, &config)
+    );
+    let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+    a.enable_rekeying();
+    b.enable_rekeying();
+    a.rekey_min_interval = Duration::ZERO;
+    b.rekey_min_interval = Duration::ZERO;
+
+    let initial = (*a.send.raw, *b.send.raw);
+
+    // Both sides send before reading, so their rekeys cross
+    let run = |mut stream: SecureStream<tokio::io::DuplexStream>| async move {
+        for i in 0..20 {
+            stream.send(BlackPacket::Ping(i)).await.unwrap();
+
+            match stream.next().await {
+                Some(Ok(BlackPacket::Ping(n))) => assert_eq!(n, i),
+                n => panic!("Unexpected packet: {:?}", n),
+            }
+        }
+
+        stream
+    };
+
+    let (a, b) = tokio::join!(run(a), run(b));
+
+    assert_ne!(*a.send.raw, initial.0);
+    assert_ne!(*b.send.raw, initial.1);
+
+    // The last `Switch` of either side may not have been read yet
+    let matches = |receive: &SecureStream<_>, send: &SecureStream<_>| {
+        *receive.receive.raw == *send.send.raw
+            || receive
+                .next_receive
+                .as_ref()
+                .is_some_and(|x| *x.raw == *send.send.raw)
+    };
+
+    assert!(matches(&a, &b));
+    assert!(matches(&b, &a));
+}
+
+#[tokio::test]
+async fn rekey_limit() {
+    use futures::{SinkExt, StreamExt};
+
+    let config = Connections {
+        rekey_messages: 1,
+        ..Default::default()
+    };
+
+    let (a, b) = tokio::io::duplex(1 << 20);
+    let (a, b) = tokio::join!(
+        SecureStream::new(a, true, &config),
+        SecureStream::new(b, false, &config)
+    );
+    let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+    // Asks for a rekey with every packet, faster than the other side accepts
+    b.enable_rekeying();
+    b.rekey_min_interval = Duration::ZERO;
+
+    for i in 0..4 {
+        b.send(BlackPacket::Ping(i)).await.unwrap();
+
+        match a.next().await {
+            Some(Ok(BlackPacket::Ping(n))) => assert_eq!(n, i),
+            Some(Err(BlackedoutError::RateLimited)) => return assert_eq!(i, 3),
+            n => panic!("Unexpected packet: {:?}", n),
+        }
+
+        a.send(BlackPacket::Pong(i)).await.unwrap();
+        b.next().await.unwrap().unwrap();
+    }
+
+    panic!("The rekeys weren't limited");
 }
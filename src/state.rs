@@ -1,10 +1,10 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use tokio::sync::mpsc::Sender;
 
 use crate::{
-    config::Config,
-    connections::model::Data,
+    config::{Config, Connections},
+    connections::{transfer::Transfers, ToPeer},
     error::Result,
     tor::onion::{get_onion_data, Onion},
     types::PublicKey,
@@ -12,11 +12,15 @@ use crate::{
 
 pub struct State {
     pub addresses: HashMap<PublicKey, AddressState>,
+    pub transfers: Transfers,
+    pub connections: Connections,
 }
 
 pub struct AddressState {
     pub onion: Onion,
-    pub connected_peers: HashMap<PublicKey, Sender<([u8; 12], Data)>>,
+    pub connected_peers: HashMap<PublicKey, Sender<ToPeer>>,
+    /// Latest round-trip time of every connected peer that answered a ping
+    pub round_trips: HashMap<PublicKey, Duration>,
 }
 
 impl State {
@@ -30,10 +34,13 @@ impl State {
                         AddressState {
                             onion: v,
                             connected_peers: Default::default(),
+                            round_trips: Default::default(),
                         },
                     )
                 })
                 .collect(),
+            transfers: Transfers::new(config.files.directory(), config.files.shared()),
+            connections: config.connections.clone(),
         })
     }
 }
@@ -0,0 +1,387 @@
+use std::{collections::HashSet, fs, io::Write, path::Path};
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_with::{base64::Base64, serde_as};
+
+use crate::{
+    client::model::{AddContact, FetchHistory, PeerHostPair, MAX_HISTORY_LIMIT},
+    error::{BlackedoutError, Result},
+    types::MessageId,
+};
+
+use super::{
+    cipher::{Cipher, SALT_LENGTH},
+    model::{Contact, Message, NewMessage, Reaction},
+    StorageBackend,
+};
+
+pub const FORMAT: &str = "blackedoutchat-archive";
+/// Version 2 added reactions
+pub const VERSION: u32 = 2;
+
+const MANIFEST: &str = "manifest.json";
+const CONTACTS: &str = "contacts.jsonl";
+const MESSAGES: &str = "messages.jsonl";
+const REACTIONS: &str = "reactions.jsonl";
+
+/// Describes the rest of the archive. Always stored in plaintext.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Manifest {
+    pub format: String,
+    pub version: u32,
+    pub created: i64,
+    pub encryption: Option<Encryption>,
+}
+
+/// The passphrase is stretched with Argon2id and the salt below into an AES-256-GCM key that
+/// encrypts every other file whole
+#[serde_as]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Encryption {
+    pub kdf: String,
+    #[serde_as(as = "Base64")]
+    pub salt: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct Archive {
+    pub contacts: Vec<Contact>,
+    pub messages: Vec<NewMessage>,
+    pub reactions: Vec<ArchivedReaction>,
+}
+
+/// One emoji of one side on a message
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ArchivedReaction {
+    #[serde(flatten)]
+    pub pair: PeerHostPair,
+    pub target_id: MessageId,
+    #[serde(flatten)]
+    pub reaction: Reaction,
+}
+
+impl Archive {
+    /// Collects one conversation, or every conversation when `pair` is `None`
+    pub fn collect(backend: &mut dyn StorageBackend, pair: Option<&PeerHostPair>) -> Result<Self> {
+        let pairs = match pair {
+            Some(pair) => vec![pair.clone()],
+            None => backend.conversations()?,
+        };
+
+        let mut archive = Archive {
+            contacts: backend
+                .contacts()?
+                .into_iter()
+                .filter(|x| pair.is_none_or(|pair| x.pair == *pair))
+                .collect(),
+            messages: Vec::new(),
+            reactions: Vec::new(),
+        };
+
+        for pair in pairs {
+            let history = history(backend, &pair)?;
+            let message_ids = history
+                .iter()
+                .filter_map(|x| x.message_id)
+                .collect::<Vec<_>>();
+
+            for (target_id, reactions) in backend.reactions(&pair, &message_ids)? {
+                archive
+                    .reactions
+                    .extend(reactions.into_iter().map(|reaction| ArchivedReaction {
+                        pair: pair.clone(),
+                        target_id,
+                        reaction,
+                    }));
+            }
+
+            archive
+                .messages
+                .extend(history.into_iter().map(NewMessage::from));
+        }
+
+        Ok(archive)
+    }
+
+    /// Adds contacts, messages and reactions the backend doesn't have yet, so importing the same
+    /// archive twice changes nothing. Existing contacts keep their local metadata. Returns the number
+    /// of contacts and messages added.
+    pub fn merge(&self, backend: &mut dyn StorageBackend) -> Result<(usize, usize)> {
+        let existing = backend.contacts()?;
+        let mut contacts = 0;
+
+        for contact in &self.contacts {
+            if existing.iter().any(|x| x.pair == contact.pair) {
+                continue;
+            }
+
+            backend.add_contact(
+                &AddContact {
+                    pair: contact.pair.clone(),
+                    metadata: contact.metadata.clone(),
+                },
+                contact.first_seen,
+            )?;
+
+            if let Some(last_seen) = contact.last_seen {
+                backend.touch_contact(&contact.pair, last_seen)?;
+            }
+
+            contacts += 1;
+        }
+
+        let mut messages = self.messages.iter().collect::<Vec<_>>();
+        messages.sort_by_key(|x| x.timestamp);
+
+        let mut pairs = Vec::<&PeerHostPair>::new();
+        let mut stored = HashSet::new();
+
+        for message in &messages {
+            if !pairs.contains(&&message.pair) {
+                pairs.push(&message.pair);
+            }
+        }
+
+        for pair in pairs {
+            for x in history(backend, pair)? {
+                stored.insert(serde_json::to_string(&NewMessage::from(x))?);
+            }
+        }
+
+        let mut added = 0;
+
+        for message in messages {
+            // Also skips messages repeated within the archive
+            if stored.insert(serde_json::to_string(message)?) {
+                backend.insert(message)?;
+                added += 1;
+            }
+        }
+
+        for x in &self.reactions {
+            backend.react(
+                &x.pair,
+                &x.target_id,
+                x.reaction.direction,
+                &x.reaction.emoji,
+                false,
+            )?;
+        }
+
+        Ok((contacts, added))
+    }
+
+    /// Writes the archive into a new directory, encrypted when a passphrase is given
+    pub fn write(&self, path: &Path, passphrase: Option<&str>) -> Result<()> {
+        fs::create_dir(path)?;
+
+        let (encryption, cipher) = match passphrase {
+            Some(passphrase) => {
+                let mut salt = vec![0u8; SALT_LENGTH];
+                rand::thread_rng().fill_bytes(&mut salt);
+
+                let cipher = Cipher::from_passphrase(passphrase, &salt)?;
+
+                (
+                    Some(Encryption {
+                        kdf: "argon2id".to_string(),
+                        salt,
+                    }),
+                    Some(cipher),
+                )
+            }
+            None => (None, None),
+        };
+
+        fs::write(
+            path.join(MANIFEST),
+            serde_json::to_vec_pretty(&Manifest {
+                format: FORMAT.to_string(),
+                version: VERSION,
+                created: chrono::Utc::now().timestamp_millis(),
+                encryption,
+            })?,
+        )?;
+
+        write_lines(&path.join(CONTACTS), &self.contacts, cipher.as_ref())?;
+        write_lines(&path.join(MESSAGES), &self.messages, cipher.as_ref())?;
+        write_lines(&path.join(REACTIONS), &self.reactions, cipher.as_ref())
+    }
+
+    /// Reads an archive, calling `passphrase` only if it is encrypted
+    pub fn read(path: &Path, passphrase: impl FnOnce() -> Result<String>) -> Result<Self> {
+        let manifest: Manifest = serde_json::from_slice(&fs::read(path.join(MANIFEST))?)
+            .map_err(|_| BlackedoutError::NotAnArchive)?;
+
+        if manifest.format != FORMAT {
+            return Err(BlackedoutError::NotAnArchive);
+        }
+
+        if manifest.version > VERSION {
+            return Err(BlackedoutError::ArchiveTooNew);
+        }
+
+        let cipher = match manifest.encryption {
+            Some(encryption) => Some(Cipher::from_passphrase(&passphrase()?, &encryption.salt)?),
+            None => None,
+        };
+
+        Ok(Archive {
+            contacts: read_lines(&path.join(CONTACTS), cipher.as_ref())?,
+            messages: read_lines(&path.join(MESSAGES), cipher.as_ref())?,
+            reactions: match manifest.version {
+                1 => Vec::new(),
+                _ => read_lines(&path.join(REACTIONS), cipher.as_ref())?,
+            },
+        })
+    }
+}
+
+/// Every message of a conversation, oldest first
+fn history(backend: &mut dyn StorageBackend, pair: &PeerHostPair) -> Result<Vec<Message>> {
+    let mut messages = Vec::new();
+
+    loop {
+        let page = backend.query(&FetchHistory {
+            pair: pair.clone(),
+            before: None,
+            after: messages.last().map(|x: &Message| x.id),
+            limit: MAX_HISTORY_LIMIT,
+        })?;
+
+        if page.is_empty() {
+            return Ok(messages);
+        }
+
+        messages.extend(page);
+    }
+}
+
+fn write_lines<T: Serialize>(path: &Path, items: &[T], cipher: Option<&Cipher>) -> Result<()> {
+    let mut buffer = Vec::new();
+
+    for item in items {
+        serde_json::to_writer(&mut buffer, item)?;
+        buffer.write_all(b"\n")?;
+    }
+
+    if let Some(cipher) = cipher {
+        buffer = cipher.encrypt(&buffer)?;
+    }
+
+    fs::write(path, buffer).map_err(Into::into)
+}
+
+fn read_lines<T: for<'de> Deserialize<'de>>(
+    path: &Path,
+    cipher: Option<&Cipher>,
+) -> Result<Vec<T>> {
+    let mut buffer = fs::read(path)?;
+
+    if let Some(cipher) = cipher {
+        buffer = cipher
+            .decrypt(&buffer)
+            .map_err(|_| BlackedoutError::BadPassphrase)?;
+    }
+
+    String::from_utf8(buffer)
+        .map_err(|_| BlackedoutError::NotAnArchive)?
+        .lines()
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| Ok(serde_json::from_str(x)?))
+        .collect()
+}
+
+#[test]
+fn export_and_import() {
+    use crate::{
+        connections::model::Data,
+        storage::{
+            memory::Memory,
+            model::{ContactMetadata, Direction},
+        },
+        types::PublicKey,
+    };
+
+    let key = PublicKey::from_onion_address(
+        "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion",
+    )
+    .unwrap();
+    let pair = PeerHostPair {
+        peer_public_key: key,
+        host_public_key: key,
+    };
+
+    let mut source = Memory::default();
+    let message_id = MessageId::random();
+
+    for i in 0..3 {
+        source
+            .insert(&NewMessage {
+                pair: pair.clone(),
+                direction: Direction::Outgoing,
+                timestamp: i,
+                message_id: (i == 2).then_some(message_id),
+                sent_at: None,
+                data: Data::Message(i.to_string()),
+            })
+            .unwrap();
+    }
+
+    source
+        .add_contact(
+            &AddContact {
+                pair: pair.clone(),
+                metadata: ContactMetadata {
+                    nickname: "bob".to_string(),
+                    ..Default::default()
+                },
+            },
+            0,
+        )
+        .unwrap();
+
+    source
+        .react(&pair, &message_id, Direction::Incoming, "👍", false)
+        .unwrap();
+
+    for passphrase in [None, Some("hunter2")] {
+        let path = std::env::temp_dir().join(format!("blackedout-{}", rand::random::<u64>()));
+
+        Archive::collect(&mut source, Some(&pair))
+            .unwrap()
+            .write(&path, passphrase)
+            .unwrap();
+
+        let read = |x: &str| Archive::read(&path, || Ok(x.to_string()));
+
+        if passphrase.is_some() {
+            assert!(matches!(read("wrong"), Err(BlackedoutError::BadPassphrase)));
+        }
+
+        let archive = read(passphrase.unwrap_or_default()).unwrap();
+        let mut destination = Memory::default();
+
+        destination
+            .insert(&NewMessage {
+                pair: pair.clone(),
+                direction: Direction::Outgoing,
+                timestamp: 1,
+                message_id: None,
+                sent_at: None,
+                data: Data::Message("1".to_string()),
+            })
+            .unwrap();
+
+        assert_eq!(archive.merge(&mut destination).unwrap(), (1, 2));
+        assert_eq!(archive.merge(&mut destination).unwrap(), (0, 0));
+        assert_eq!(destination.contacts().unwrap()[0].metadata.nickname, "bob");
+        assert_eq!(
+            destination.reactions(&pair, &[message_id]).unwrap()[&message_id],
+            source.reactions(&pair, &[message_id]).unwrap()[&message_id]
+        );
+
+        fs::remove_dir_all(path).ok();
+    }
+}
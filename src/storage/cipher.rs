@@ -0,0 +1,134 @@
+use std::env;
+
+use aes_gcm::{
+    aead::{generic_array::GenericArray, AeadInPlace, NewAead},
+    Aes256Gcm,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::RngCore;
+
+use crate::error::{BlackedoutError, Result};
+
+pub const KEY_LENGTH: usize = 32;
+pub const SALT_LENGTH: usize = 16;
+
+// Argon2id with 19 MiB of memory and two passes
+const ARGON2_MEMORY: u32 = 19 * 1024;
+const ARGON2_PASSES: u32 = 2;
+
+/// Passphrases given at startup for storages with encryption enabled. When `new` is set the
+/// stored keys are wrapped again with it so the old passphrase stops working.
+pub struct Passphrase {
+    pub current: String,
+    pub new: Option<String>,
+}
+
+impl Passphrase {
+    /// Reads `BLACKEDOUT_PASSPHRASE` and `BLACKEDOUT_NEW_PASSPHRASE`, prompting on the terminal
+    /// if the current passphrase is not set
+    pub fn read() -> Result<Self> {
+        let current = match env::var("BLACKEDOUT_PASSPHRASE") {
+            Ok(n) => n,
+            Err(_) => rpassword::prompt_password("Storage passphrase: ")?,
+        };
+
+        if current.is_empty() {
+            return Err(BlackedoutError::MissingPassphrase);
+        }
+
+        Ok(Passphrase {
+            current,
+            new: env::var("BLACKEDOUT_NEW_PASSPHRASE")
+                .ok()
+                .filter(|x| !x.is_empty()),
+        })
+    }
+}
+
+/// AES-256-GCM with the nonce and tag stored in front of the ciphertext
+#[derive(Clone)]
+pub struct Cipher(Aes256Gcm);
+
+impl Cipher {
+    pub fn new(key: &[u8; KEY_LENGTH]) -> Self {
+        Cipher(Aes256Gcm::new(GenericArray::from_slice(key)))
+    }
+
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> Result<Self> {
+        let mut key = [0u8; KEY_LENGTH];
+
+        Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(ARGON2_MEMORY, ARGON2_PASSES, 1, Some(KEY_LENGTH))?,
+        )
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)?;
+
+        Ok(Cipher::new(&key))
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut ciphertext = vec![0u8; 28];
+        ciphertext.extend_from_slice(plaintext);
+
+        let (nonce, rest) = ciphertext.split_at_mut(12);
+        let (tag, buffer) = rest.split_at_mut(16);
+
+        rand::thread_rng().fill_bytes(nonce);
+
+        tag.clone_from_slice(
+            self.0
+                .encrypt_in_place_detached(GenericArray::from_slice(nonce), b"", buffer)
+                .map_err(|_| BlackedoutError::AesEncryptionError)?
+                .as_slice(),
+        );
+
+        Ok(ciphertext)
+    }
+
+    pub fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if ciphertext.len() < 28 {
+            return Err(BlackedoutError::AesBadLength);
+        }
+
+        let (nonce, rest) = ciphertext.split_at(12);
+        let (tag, buffer) = rest.split_at(16);
+        let mut plaintext = buffer.to_vec();
+
+        self.0
+            .decrypt_in_place_detached(
+                GenericArray::from_slice(nonce),
+                b"",
+                &mut plaintext,
+                GenericArray::from_slice(tag),
+            )
+            .map_err(|_| BlackedoutError::AesBadTag)?;
+
+        Ok(plaintext)
+    }
+}
+
+pub fn random_key() -> [u8; KEY_LENGTH] {
+    let mut key = [0u8; KEY_LENGTH];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+/// Encrypts the data key with a key derived from the passphrase and a fresh salt. Returns the
+/// salt and the wrapped key to store.
+pub fn wrap_key(passphrase: &str, key: &[u8; KEY_LENGTH]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut salt = vec![0u8; SALT_LENGTH];
+    rand::thread_rng().fill_bytes(&mut salt);
+
+    let wrapped = Cipher::from_passphrase(passphrase, &salt)?.encrypt(key)?;
+
+    Ok((salt, wrapped))
+}
+
+pub fn unwrap_key(passphrase: &str, salt: &[u8], wrapped: &[u8]) -> Result<[u8; KEY_LENGTH]> {
+    Cipher::from_passphrase(passphrase, salt)?
+        .decrypt(wrapped)
+        .map_err(|_| BlackedoutError::BadPassphrase)?
+        .try_into()
+        .map_err(|_| BlackedoutError::BadPassphrase)
+}
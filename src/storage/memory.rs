@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+
+use crate::{
+    client::model::{
+        AddContact, DeleteHistory, FetchHistory, PeerHostPair, Search, MAX_HISTORY_LIMIT,
+    },
+    error::{BlackedoutError, Result},
+    types::MessageId,
+};
+
+use super::{
+    cipher::Passphrase,
+    model::{
+        Contact, ContactMetadata, Direction, Message, NewMessage, OutboxEntry, Reaction,
+        SearchResult,
+    },
+    search::{snippet, terms},
+    StorageBackend,
+};
+
+/// Keeps messages in memory only so nothing survives a restart
+#[derive(Default)]
+pub struct Memory {
+    messages: Vec<Message>,
+    next_id: i64,
+    contacts: Vec<Contact>,
+    outbox: Vec<OutboxEntry>,
+    reactions: Vec<(PeerHostPair, MessageId, Reaction)>,
+    identity_keys: HashMap<PeerHostPair, Vec<u8>>,
+}
+
+impl Memory {
+    fn contact(&mut self, pair: &PeerHostPair) -> Option<&mut Contact> {
+        self.contacts.iter_mut().find(|x| x.pair == *pair)
+    }
+
+    /// Deletes the reactions of a conversation whose message is no longer stored
+    fn prune_reactions(&mut self, pair: &PeerHostPair) {
+        let messages = &self.messages;
+
+        self.reactions.retain(|(x, target_id, _)| {
+            x != pair
+                || messages
+                    .iter()
+                    .any(|y| y.pair == *pair && y.message_id.as_ref() == Some(target_id))
+        });
+    }
+}
+
+impl StorageBackend for Memory {
+    fn migrate(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn unlock(&mut self, _passphrase: Option<&Passphrase>) -> Result<()> {
+        Ok(())
+    }
+
+    fn insert(&mut self, message: &NewMessage) -> Result<()> {
+        if message.message_id.is_some()
+            && self.messages.iter().any(|x| {
+                x.pair == message.pair
+                    && x.direction == message.direction
+                    && x.message_id == message.message_id
+            })
+        {
+            return Ok(());
+        }
+
+        self.next_id += 1;
+        self.messages.push(Message {
+            id: self.next_id,
+            pair: message.pair.clone(),
+            direction: message.direction,
+            timestamp: message.timestamp,
+            message_id: message.message_id,
+            sent_at: message.sent_at,
+            data: message.data.clone(),
+            reactions: Vec::new(),
+        });
+
+        Ok(())
+    }
+
+    fn query(&mut self, query: &FetchHistory) -> Result<Vec<Message>> {
+        let limit = query.limit.clamp(1, MAX_HISTORY_LIMIT) as usize;
+        let matches = self.messages.iter().filter(|x| {
+            x.pair == query.pair
+                && query.before.is_none_or(|before| x.id < before)
+                && query.after.is_none_or(|after| x.id > after)
+        });
+
+        // Messages are kept in ID order so pages can be cut from either end
+        let mut messages = match query.after {
+            Some(_) => matches.take(limit).cloned().collect::<Vec<_>>(),
+            None => matches.rev().take(limit).cloned().collect(),
+        };
+
+        if query.after.is_none() {
+            messages.reverse();
+        }
+
+        Ok(messages)
+    }
+
+    fn message(
+        &mut self,
+        pair: &PeerHostPair,
+        message_id: &MessageId,
+        direction: Direction,
+    ) -> Result<Option<Message>> {
+        Ok(self
+            .messages
+            .iter()
+            .find(|x| {
+                x.pair == *pair
+                    && x.direction == direction
+                    && x.message_id.as_ref() == Some(message_id)
+            })
+            .cloned())
+    }
+
+    fn delete_message(
+        &mut self,
+        pair: &PeerHostPair,
+        message_id: &MessageId,
+        direction: Direction,
+    ) -> Result<usize> {
+        let len = self.messages.len();
+        self.messages.retain(|x| {
+            x.pair != *pair
+                || x.direction != direction
+                || (x.message_id.as_ref() != Some(message_id)
+                    && x.data.target() != Some(message_id))
+        });
+
+        // Reactions don't say which side's message they are for, so they stay while the other
+        // side still has a message with this ID
+        if self.message(pair, message_id, direction.other())?.is_none() {
+            self.reactions
+                .retain(|(x, target_id, _)| x != pair || target_id != message_id);
+        }
+
+        Ok(len - self.messages.len())
+    }
+
+    fn react(
+        &mut self,
+        pair: &PeerHostPair,
+        target_id: &MessageId,
+        direction: Direction,
+        emoji: &str,
+        remove: bool,
+    ) -> Result<()> {
+        let reaction = Reaction {
+            emoji: emoji.to_string(),
+            direction,
+        };
+        let position = self
+            .reactions
+            .iter()
+            .position(|x| x.0 == *pair && x.1 == *target_id && x.2 == reaction);
+
+        match (position, remove) {
+            (Some(i), true) => {
+                self.reactions.remove(i);
+            }
+            (None, false) => self.reactions.push((pair.clone(), *target_id, reaction)),
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn reactions(
+        &mut self,
+        pair: &PeerHostPair,
+        message_ids: &[MessageId],
+    ) -> Result<HashMap<MessageId, Vec<Reaction>>> {
+        let mut grouped = HashMap::<MessageId, Vec<Reaction>>::new();
+
+        for (_, target_id, reaction) in self
+            .reactions
+            .iter()
+            .filter(|x| x.0 == *pair && message_ids.contains(&x.1))
+        {
+            grouped
+                .entry(*target_id)
+                .or_default()
+                .push(reaction.clone());
+        }
+
+        // Grouped by side like the SQL backends do
+        for reactions in grouped.values_mut() {
+            reactions.sort_by_key(|x| x.direction as i32);
+        }
+
+        Ok(grouped)
+    }
+
+    fn delete(&mut self, delete: &DeleteHistory) -> Result<usize> {
+        let len = self.messages.len();
+        self.messages.retain(|x| {
+            x.pair != delete.pair || delete.until.is_some_and(|until| x.timestamp > until)
+        });
+        self.prune_reactions(&delete.pair);
+
+        Ok(len - self.messages.len())
+    }
+
+    fn conversations(&mut self) -> Result<Vec<PeerHostPair>> {
+        let mut pairs = Vec::<PeerHostPair>::new();
+
+        for message in &self.messages {
+            if !pairs.contains(&message.pair) {
+                pairs.push(message.pair.clone());
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    fn truncate(&mut self, pair: &PeerHostPair, keep: usize) -> Result<usize> {
+        let len = self.messages.len();
+        let mut kept = 0;
+
+        // Walk from the newest so the first `keep` of the conversation survive
+        self.messages.reverse();
+        self.messages.retain(|x| {
+            if x.pair != *pair {
+                return true;
+            }
+
+            kept += 1;
+            kept <= keep
+        });
+        self.messages.reverse();
+        self.prune_reactions(pair);
+
+        Ok(len - self.messages.len())
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn search(&mut self, search: &Search) -> Result<Vec<SearchResult>> {
+        let terms = terms(&search.query);
+
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        Ok(self
+            .messages
+            .iter()
+            .rev()
+            .filter(|x| {
+                search
+                    .host_public_key
+                    .is_none_or(|host| x.pair.host_public_key == host)
+                    && search
+                        .peer_public_key
+                        .is_none_or(|peer| x.pair.peer_public_key == peer)
+                    && search.since.is_none_or(|since| x.timestamp >= since)
+                    && search.until.is_none_or(|until| x.timestamp <= until)
+            })
+            .filter_map(|x| {
+                snippet(&x.data, &terms).map(|snippet| SearchResult {
+                    message: x.clone(),
+                    snippet,
+                })
+            })
+            .take(search.limit.clamp(1, MAX_HISTORY_LIMIT) as usize)
+            .collect())
+    }
+
+    fn add_contact(&mut self, contact: &AddContact, timestamp: i64) -> Result<()> {
+        match self.contact(&contact.pair) {
+            Some(x) => x.metadata = contact.metadata.clone(),
+            None => self.contacts.push(Contact {
+                pair: contact.pair.clone(),
+                metadata: contact.metadata.clone(),
+                first_seen: timestamp,
+                last_seen: None,
+            }),
+        }
+
+        Ok(())
+    }
+
+    fn rename_contact(&mut self, pair: &PeerHostPair, nickname: &str) -> Result<()> {
+        self.contact(pair)
+            .ok_or(BlackedoutError::ContactDoesNotExist)?
+            .metadata
+            .nickname = nickname.to_string();
+
+        Ok(())
+    }
+
+    fn remove_contact(&mut self, pair: &PeerHostPair) -> Result<usize> {
+        let len = self.contacts.len();
+        self.contacts.retain(|x| x.pair != *pair);
+
+        Ok(len - self.contacts.len())
+    }
+
+    fn touch_contact(&mut self, pair: &PeerHostPair, timestamp: i64) -> Result<()> {
+        match self.contact(pair) {
+            Some(x) => x.last_seen = Some(timestamp),
+            None => self.contacts.push(Contact {
+                pair: pair.clone(),
+                metadata: ContactMetadata::default(),
+                first_seen: timestamp,
+                last_seen: Some(timestamp),
+            }),
+        }
+
+        Ok(())
+    }
+
+    fn contacts(&mut self) -> Result<Vec<Contact>> {
+        Ok(self.contacts.clone())
+    }
+
+    fn enqueue(&mut self, entry: &OutboxEntry) -> Result<()> {
+        self.outbox.push(entry.clone());
+        Ok(())
+    }
+
+    fn outbox(&mut self, pair: Option<&PeerHostPair>) -> Result<Vec<OutboxEntry>> {
+        Ok(self
+            .outbox
+            .iter()
+            .filter(|x| pair.is_none_or(|pair| x.pair == *pair))
+            .cloned()
+            .collect())
+    }
+
+    fn outbox_pairs(&mut self) -> Result<Vec<PeerHostPair>> {
+        let mut pairs = Vec::<PeerHostPair>::new();
+
+        for entry in &self.outbox {
+            if !pairs.contains(&entry.pair) {
+                pairs.push(entry.pair.clone());
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    fn dequeue(&mut self, message_id: &MessageId) -> Result<usize> {
+        let len = self.outbox.len();
+        self.outbox.retain(|x| x.envelope.message_id != *message_id);
+
+        Ok(len - self.outbox.len())
+    }
+
+    fn identity_key(&mut self, pair: &PeerHostPair) -> Result<Option<Vec<u8>>> {
+        Ok(self.identity_keys.get(pair).cloned())
+    }
+
+    fn pin_identity_key(&mut self, pair: &PeerHostPair, key: &[u8], _timestamp: i64) -> Result<()> {
+        self.identity_keys
+            .entry(pair.clone())
+            .or_insert_with(|| key.to_vec());
+
+        Ok(())
+    }
+
+    fn unpin_identity_key(&mut self, pair: &PeerHostPair) -> Result<usize> {
+        Ok(self.identity_keys.remove(pair).map_or(0, |_| 1))
+    }
+}
+
+#[test]
+fn memory_backend() {
+    super::test_backend(&mut Memory::default());
+}
@@ -0,0 +1,144 @@
+use std::io::sink;
+
+use diesel::{
+    connection::SimpleConnection,
+    migration::{Migration, RunMigrationsError},
+};
+use diesel_migrations::{run_migrations, setup_database, MigrationConnection};
+
+use crate::error::{BlackedoutError, Result};
+
+/// A diesel migration compiled into the binary from `migrations/<backend>/<version>_<name>`
+pub struct EmbeddedMigration {
+    pub version: &'static str,
+    pub up_sql: &'static str,
+    pub down_sql: &'static str,
+    /// Drops or rewrites stored data, so the database is backed up before it runs
+    pub destructive: bool,
+}
+
+macro_rules! embed_migration {
+    ($backend:literal, $version:literal, $name:literal) => {
+        embed_migration!($backend, $version, $name, false)
+    };
+    ($backend:literal, $version:literal, $name:literal, destructive) => {
+        embed_migration!($backend, $version, $name, true)
+    };
+    ($backend:literal, $version:literal, $name:literal, $destructive:literal) => {
+        $crate::storage::migrations::EmbeddedMigration {
+            version: $version,
+            up_sql: include_str!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/migrations/",
+                $backend,
+                "/",
+                $version,
+                "_",
+                $name,
+                "/up.sql"
+            )),
+            down_sql: include_str!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/migrations/",
+                $backend,
+                "/",
+                $version,
+                "_",
+                $name,
+                "/down.sql"
+            )),
+            destructive: $destructive,
+        }
+    };
+}
+
+pub(super) use embed_migration;
+
+impl Migration for EmbeddedMigration {
+    fn version(&self) -> &str {
+        self.version
+    }
+
+    fn run(&self, conn: &dyn SimpleConnection) -> std::result::Result<(), RunMigrationsError> {
+        conn.batch_execute(self.up_sql).map_err(Into::into)
+    }
+
+    fn revert(&self, conn: &dyn SimpleConnection) -> std::result::Result<(), RunMigrationsError> {
+        conn.batch_execute(self.down_sql).map_err(Into::into)
+    }
+}
+
+/// Runs the pending migrations, which are recorded in `__diesel_schema_migrations`. Databases
+/// that have a migration this binary doesn't know about were written by a newer version and are
+/// refused. `backup` is called with the current schema version before destructive migrations.
+pub fn run<C, F>(conn: &C, migrations: &[EmbeddedMigration], backup: F) -> Result<()>
+where
+    C: MigrationConnection,
+    F: FnOnce(&str) -> Result<()>,
+{
+    setup_database(conn)?;
+
+    let applied = conn.previously_run_migration_versions()?;
+
+    if applied
+        .iter()
+        .any(|x| !migrations.iter().any(|y| y.version == x))
+    {
+        return Err(BlackedoutError::StorageTooNew);
+    }
+
+    let pending = migrations
+        .iter()
+        .filter(|x| !applied.contains(x.version))
+        .collect::<Vec<_>>();
+
+    if let Some(version) = applied.iter().max() {
+        if pending.iter().any(|x| x.destructive) {
+            backup(version)?;
+        }
+    }
+
+    run_migrations(
+        conn,
+        pending.into_iter().map(|x| x as &dyn Migration),
+        &mut sink(),
+    )
+    .map_err(Into::into)
+}
+
+#[cfg(feature = "storage-sqlite")]
+#[test]
+fn newer_and_destructive_migrations() {
+    use diesel::{sqlite::SqliteConnection, Connection};
+
+    let conn = SqliteConnection::establish(":memory:").unwrap();
+    let migration = |version, destructive| EmbeddedMigration {
+        version,
+        up_sql: "",
+        down_sql: "",
+        destructive,
+    };
+
+    let mut backups = Vec::new();
+    let mut run = |migrations: &[EmbeddedMigration]| {
+        run(&conn, migrations, |version| {
+            backups.push(version.to_string());
+            Ok(())
+        })
+    };
+
+    run(&[migration("1", true)]).unwrap();
+    run(&[migration("1", true), migration("2", false)]).unwrap();
+    run(&[
+        migration("1", true),
+        migration("2", false),
+        migration("3", true),
+    ])
+    .unwrap();
+
+    assert!(matches!(
+        run(&[migration("1", true)]),
+        Err(BlackedoutError::StorageTooNew)
+    ));
+    assert_eq!(backups, ["2"]);
+}
@@ -1,47 +1,572 @@
+pub mod archive;
+pub mod cipher;
+pub mod memory;
+mod migrations;
+pub mod model;
+#[cfg(feature = "storage-postgres")]
+pub mod postgres;
+mod retention;
+mod schema;
+mod search;
+mod sql;
+#[cfg(feature = "storage-sqlite")]
+pub mod sqlite;
+
 use std::{
+    collections::HashMap,
     sync::{Arc, Mutex},
     thread,
 };
 
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::{
+    mpsc::{channel, Receiver, Sender},
+    oneshot,
+};
+
+use crate::client::model::{
+    AddContact, ClientPacket, DeleteHistory, FetchHistory, PeerHostPair, Search,
+};
+use crate::config::{Config, Storage as StorageConfig};
+use crate::connections::model::{Data, Envelope};
+use crate::error::{BlackedoutError, Result};
+use crate::types::MessageId;
+
+use self::archive::Archive;
+use self::cipher::Passphrase;
+use self::model::{
+    Contact, ContactMetadata, Direction, Message, NewMessage, OutboxEntry, Reaction, SearchResult,
+};
+
+pub trait StorageBackend: Send {
+    /// Brings the backend's schema up to date, called once when storage is opened. Refuses
+    /// databases written by a newer version.
+    fn migrate(&mut self) -> Result<()>;
+
+    /// Called after `migrate`. With a passphrase the stored payloads are encrypted, and any
+    /// existing plaintext is encrypted the first time. Without one an encrypted backend is refused.
+    fn unlock(&mut self, passphrase: Option<&Passphrase>) -> Result<()>;
+
+    /// Does nothing when the conversation already has a message with the same ID and
+    /// direction, as peers send envelopes again when their ack was lost
+    fn insert(&mut self, message: &NewMessage) -> Result<()>;
+
+    fn query(&mut self, query: &FetchHistory) -> Result<Vec<Message>>;
+
+    /// A message of a conversation by the ID its sender gave it. IDs are only unique per sender
+    /// since a peer can reuse ours.
+    fn message(
+        &mut self,
+        pair: &PeerHostPair,
+        message_id: &MessageId,
+        direction: Direction,
+    ) -> Result<Option<Message>>;
+
+    /// Deletes a message of one side along with everything referring to it, like its edits and
+    /// reactions. Returns the number of messages deleted.
+    fn delete_message(
+        &mut self,
+        pair: &PeerHostPair,
+        message_id: &MessageId,
+        direction: Direction,
+    ) -> Result<usize>;
+
+    /// Adds an emoji to the reactions of one side to a message, or removes it
+    fn react(
+        &mut self,
+        pair: &PeerHostPair,
+        target_id: &MessageId,
+        direction: Direction,
+        emoji: &str,
+        remove: bool,
+    ) -> Result<()>;
+
+    /// The reactions to messages of a conversation, in the order they were added
+    fn reactions(
+        &mut self,
+        pair: &PeerHostPair,
+        message_ids: &[MessageId],
+    ) -> Result<HashMap<MessageId, Vec<Reaction>>>;
+
+    /// Returns the number of messages deleted
+    fn delete(&mut self, delete: &DeleteHistory) -> Result<usize>;
+
+    /// Every conversation with stored messages
+    fn conversations(&mut self) -> Result<Vec<PeerHostPair>>;
+
+    /// Deletes all but the newest `keep` messages of a conversation. Returns the number deleted.
+    fn truncate(&mut self, pair: &PeerHostPair, keep: usize) -> Result<usize>;
+
+    /// Reclaims the space of deleted data so it can't be recovered from disk
+    fn compact(&mut self) -> Result<()>;
+
+    /// Full-text search over message bodies, newest first
+    fn search(&mut self, search: &Search) -> Result<Vec<SearchResult>>;
+
+    /// Creates the contact or replaces the metadata of an existing one
+    fn add_contact(&mut self, contact: &AddContact, timestamp: i64) -> Result<()>;
+
+    fn rename_contact(&mut self, pair: &PeerHostPair, nickname: &str) -> Result<()>;
+
+    /// Returns the number of contacts removed. Their messages are kept.
+    fn remove_contact(&mut self, pair: &PeerHostPair) -> Result<usize>;
+
+    /// Updates `last_seen`, creating a contact without metadata for peers seen for the first time
+    fn touch_contact(&mut self, pair: &PeerHostPair, timestamp: i64) -> Result<()>;
+
+    fn contacts(&mut self) -> Result<Vec<Contact>>;
 
-use crate::client::model::ClientPacket;
-use crate::config::Config;
+    fn contact(&mut self, pair: &PeerHostPair) -> Result<Option<Contact>> {
+        Ok(self.contacts()?.into_iter().find(|x| x.pair == *pair))
+    }
+
+    /// Queues data until its peer acknowledges it
+    fn enqueue(&mut self, entry: &OutboxEntry) -> Result<()>;
+
+    /// Queued data in the order it was queued, of one conversation or all of them
+    fn outbox(&mut self, pair: Option<&PeerHostPair>) -> Result<Vec<OutboxEntry>>;
+
+    /// Every conversation with queued data, without reading the data itself
+    fn outbox_pairs(&mut self) -> Result<Vec<PeerHostPair>>;
+
+    /// Returns the number of entries removed
+    fn dequeue(&mut self, message_id: &MessageId) -> Result<usize>;
+
+    /// The post-quantum identity key pinned for a peer
+    fn identity_key(&mut self, pair: &PeerHostPair) -> Result<Option<Vec<u8>>>;
+
+    /// Pins the identity key of a peer unless one is already pinned
+    fn pin_identity_key(&mut self, pair: &PeerHostPair, key: &[u8], timestamp: i64) -> Result<()>;
+
+    /// Returns the number of keys removed. The next key the peer presents is pinned instead.
+    fn unpin_identity_key(&mut self, pair: &PeerHostPair) -> Result<usize>;
+}
+
+/// Applies a packet to a backend
+fn store(backend: &mut dyn StorageBackend, packet: &ClientPacket, timestamp: i64) -> Result<()> {
+    if let Some((pair, direction, envelope)) = data_of(packet) {
+        match &envelope.data {
+            // Before the tombstone is stored since it refers to the message too
+            Data::Delete { target_id } => {
+                backend.delete_message(pair, target_id, direction)?;
+            }
+            Data::Reaction {
+                target_id,
+                emoji,
+                remove,
+            } => backend.react(pair, target_id, direction, emoji, *remove)?,
+            _ => (),
+        }
+    }
+
+    if let Some(message) = NewMessage::from_packet(packet, timestamp) {
+        backend.insert(&message)?;
+    }
+
+    match packet {
+        ClientPacket::ConnectionEstablished(pair)
+        | ClientPacket::Disconnected { pair, .. }
+        | ClientPacket::DataReceived { pair, .. } => backend.touch_contact(pair, timestamp),
+        ClientPacket::SendDataConfirmation { pair, envelope, .. } => {
+            backend.dequeue(&envelope.message_id)?;
+            backend.touch_contact(pair, timestamp)
+        }
+        // The peer rejected it, so it is never going to be confirmed
+        ClientPacket::SendDataFailed { message_id, .. } => backend.dequeue(message_id).map(|_| ()),
+        ClientPacket::DeleteHistory(delete) => backend.delete(delete).map(|_| ()),
+        ClientPacket::AddContact(contact) => backend.add_contact(contact, timestamp),
+        ClientPacket::RenameContact { pair, nickname } => backend.rename_contact(pair, nickname),
+        ClientPacket::RemoveContact(pair) => backend.remove_contact(pair).map(|_| ()),
+        ClientPacket::UnpinIdentityKey(pair) => backend.unpin_identity_key(pair).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+/// Data exchanged with a peer and which way it went
+fn data_of(packet: &ClientPacket) -> Option<(&PeerHostPair, Direction, &Envelope)> {
+    match packet {
+        ClientPacket::DataReceived { pair, envelope } => {
+            Some((pair, Direction::Incoming, envelope))
+        }
+        ClientPacket::SendDataConfirmation { pair, envelope, .. } => {
+            Some((pair, Direction::Outgoing, envelope))
+        }
+        _ => None,
+    }
+}
+
+/// Longest emoji accepted in a reaction, in bytes
+pub const MAX_EMOJI_LENGTH: usize = 32;
+/// Most emojis one side may react to a message with
+pub const MAX_REACTIONS: usize = 20;
+
+/// Data referring to an earlier message needs it to exist. Anyone may react to a message but
+/// only its sender may edit or delete it. `direction` is the one of the data, so an edit
+/// received from a peer has to target a message that peer sent.
+fn check_target(
+    backend: &mut dyn StorageBackend,
+    pair: &PeerHostPair,
+    data: &Data,
+    direction: Direction,
+) -> Result<()> {
+    let target_id = match data.target() {
+        Some(n) => n,
+        None => return Ok(()),
+    };
+
+    if let Data::Reaction {
+        emoji,
+        remove: false,
+        ..
+    } = data
+    {
+        check_reaction(backend, pair, target_id, direction, emoji)?;
+    }
+
+    // Looked up by sender so a peer reusing one of our IDs can't make its data refer to ours
+    let target = match (find_target(backend, pair, target_id, direction)?, data) {
+        (Some(n), _) => n,
+        (None, Data::Reaction { .. }) => find_target(backend, pair, target_id, direction.other())?
+            .ok_or(BlackedoutError::MessageDoesNotExist)?,
+        (None, _) => {
+            return Err(
+                match find_target(backend, pair, target_id, direction.other())? {
+                    Some(_) => BlackedoutError::NotMessageSender,
+                    None => BlackedoutError::MessageDoesNotExist,
+                },
+            )
+        }
+    };
+
+    match target {
+        Data::Message(_) | Data::Reply { .. } => Ok(()),
+        _ => Err(BlackedoutError::MessageDoesNotExist),
+    }
+}
+
+/// Reactions are stored as sent, so their size is bounded here
+fn check_reaction(
+    backend: &mut dyn StorageBackend,
+    pair: &PeerHostPair,
+    target_id: &MessageId,
+    direction: Direction,
+    emoji: &str,
+) -> Result<()> {
+    if emoji.is_empty() || emoji.len() > MAX_EMOJI_LENGTH {
+        return Err(BlackedoutError::InvalidReaction);
+    }
+
+    let reactions = backend
+        .reactions(pair, &[*target_id])?
+        .remove(target_id)
+        .unwrap_or_default();
+    let emojis = reactions
+        .iter()
+        .filter(|x| x.direction == direction)
+        .collect::<Vec<_>>();
+
+    match emojis.len() < MAX_REACTIONS || emojis.iter().any(|x| x.emoji == emoji) {
+        true => Ok(()),
+        false => Err(BlackedoutError::InvalidReaction),
+    }
+}
+
+/// The data of a message sent by `sender`
+fn find_target(
+    backend: &mut dyn StorageBackend,
+    pair: &PeerHostPair,
+    target_id: &MessageId,
+    sender: Direction,
+) -> Result<Option<Data>> {
+    Ok(match backend.message(pair, target_id, sender)? {
+        Some(n) => Some(n.data),
+        // Messages to the peer are only stored once it confirmed them
+        None if sender == Direction::Outgoing => backend
+            .outbox(Some(pair))?
+            .into_iter()
+            .find(|x| x.envelope.message_id == *target_id)
+            .map(|x| x.envelope.data),
+        None => None,
+    })
+}
+
+type Backends = Arc<Mutex<Vec<Box<dyn StorageBackend>>>>;
+/// Tells `connection_loop` whether received data was accepted
+type Checked = oneshot::Sender<Result<()>>;
 
 pub struct Storage {
-    storage_tx: Sender<ClientPacket>,
+    storage_tx: Sender<(ClientPacket, Option<Checked>)>,
     subscribers: Arc<Mutex<Vec<Sender<ClientPacket>>>>,
+    backends: Backends,
+    primary: usize,
+    read_receipts: bool,
 }
 
 impl Storage {
-    pub fn new(config: &Config) -> Self {
-        let (storage_tx, mut storage_rx): (Sender<ClientPacket>, _) = channel(1);
+    pub fn new(config: &Config) -> Result<Self> {
+        let passphrase = match config.storage.storages.iter().any(StorageConfig::encrypted) {
+            true => Some(Passphrase::read()?),
+            false => None,
+        };
+
+        let backends = config
+            .storage
+            .storages
+            .iter()
+            .map(|x| {
+                let mut backend: Box<dyn StorageBackend> = match x {
+                    #[cfg(feature = "storage-sqlite")]
+                    StorageConfig::Sqlite { path, .. } => Box::new(sqlite::Sqlite::open(path)?),
+                    #[cfg(feature = "storage-postgres")]
+                    StorageConfig::Postgres { url, .. } => Box::new(postgres::Postgres::open(url)?),
+                    StorageConfig::Memory => Box::new(memory::Memory::default()),
+                };
+
+                backend.migrate()?;
+                backend.unlock(passphrase.as_ref().filter(|_| x.encrypted()))?;
+
+                Ok(backend)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let primary = config.storage.primary;
+
+        if primary >= backends.len() {
+            return Err(BlackedoutError::NoStorageBackend);
+        }
+
+        let backends = Arc::new(Mutex::new(backends));
+        let backends0 = backends.clone();
+
+        let (storage_tx, mut storage_rx): (Sender<(ClientPacket, Option<Checked>)>, _) = channel(1);
         let subscribers = Arc::new(Mutex::new(Vec::new()));
         let sub0 = subscribers.clone();
 
         thread::spawn(move || {
-            while let Some(n) = storage_rx.blocking_recv() {
+            while let Some((n, checked)) = storage_rx.blocking_recv() {
+                // The pair of received data is the authenticated one of the connection. Checked
+                // here rather than in `connection_loop` so the target is already stored.
+                if let ClientPacket::DataReceived { pair, envelope } = &n {
+                    let result = check_target(
+                        backends0.lock().unwrap()[primary].as_mut(),
+                        pair,
+                        &envelope.data,
+                        Direction::Incoming,
+                    );
+                    let accepted = result.is_ok();
+
+                    match (checked, result) {
+                        (Some(checked), result) => {
+                            checked.send(result).ok();
+                        }
+                        (None, Err(e)) => println!("Rejected data from peer: {:?}", e),
+                        (None, Ok(_)) => {}
+                    }
+
+                    if !accepted {
+                        continue;
+                    }
+                }
+
                 // Send to subscribed clients
-                sub0.lock()
-                    .unwrap()
-                    .clone()
-                    .into_iter()
-                    .for_each(|x: Sender<ClientPacket>| {
-                        x.blocking_send(n.clone()).ok();
-                    });
-
-                // TODO: Store in database
+                let fan_out =
+                    |packet: ClientPacket| {
+                        sub0.lock().unwrap().clone().into_iter().for_each(
+                            |x: Sender<ClientPacket>| {
+                                x.blocking_send(packet.clone()).ok();
+                            },
+                        );
+                    };
+
+                fan_out(n.clone());
+
+                // Store in every database
+                let timestamp = chrono::Utc::now().timestamp_millis();
+
+                for backend in backends0.lock().unwrap().iter_mut() {
+                    if let Err(e) = store(backend.as_mut(), &n, timestamp) {
+                        println!("Error storing packet: {:?}", e);
+                    }
+                }
+
+                // Clients get the whole set of a message after every change
+                if let Some((pair, _, envelope)) = data_of(&n) {
+                    if let Data::Reaction { target_id, .. } = &envelope.data {
+                        match backends0.lock().unwrap()[primary].reactions(pair, &[*target_id]) {
+                            Ok(mut reactions) => fan_out(ClientPacket::Reactions {
+                                pair: pair.clone(),
+                                target_id: *target_id,
+                                reactions: reactions.remove(target_id).unwrap_or_default(),
+                            }),
+                            Err(e) => println!("Error reading reactions: {:?}", e),
+                        }
+                    }
+                }
             }
         });
 
-        Storage {
+        Ok(Storage {
             storage_tx,
             subscribers,
-        }
+            backends,
+            primary,
+            read_receipts: config.messaging.read_receipts,
+        })
+    }
+
+    /// Starts enforcing retention policies. Called once Tor has started since the policies of
+    /// host addresses are looked up by their hostname.
+    pub fn start_retention(&self, config: &Config) {
+        retention::spawn(
+            self.backends.clone(),
+            config.addresses.addresses.clone(),
+            config.storage.retention,
+        );
     }
 
     pub async fn send_packet(&self, packet: ClientPacket) {
-        self.storage_tx.send(packet).await.unwrap();
+        self.storage_tx.send((packet, None)).await.unwrap();
+    }
+
+    /// Like `send_packet` for data received from a peer, but waits until it was checked against
+    /// what is stored before it. Fails if it was rejected and not stored.
+    pub async fn receive_checked(&self, pair: PeerHostPair, envelope: Envelope) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+
+        self.storage_tx
+            .send((ClientPacket::DataReceived { pair, envelope }, Some(tx)))
+            .await
+            .unwrap();
+
+        rx.await.map_err(|_| BlackedoutError::Unexpected)?
+    }
+
+    /// Sends a packet to subscribed clients only, bypassing every backend
+    pub async fn broadcast(&self, packet: ClientPacket) {
+        let subscribers = self.subscribers.lock().unwrap().clone();
+
+        for subscriber in subscribers {
+            subscriber.send(packet.clone()).await.ok();
+        }
+    }
+
+    /// Reads from the primary backend, with the reactions to each message. Blocks on the
+    /// database so call this from a blocking task.
+    pub fn fetch_history(&self, query: &FetchHistory) -> Result<Vec<Message>> {
+        let mut backends = self.backends.lock().unwrap();
+        let backend = &mut backends[self.primary];
+
+        let mut messages = backend.query(query)?;
+        let mut reactions = backend.reactions(
+            &query.pair,
+            &messages
+                .iter()
+                .filter_map(|x| x.message_id)
+                .collect::<Vec<_>>(),
+        )?;
+
+        for message in &mut messages {
+            if let Some(n) = message.message_id.and_then(|x| reactions.remove(&x)) {
+                message.reactions = n;
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// Reads from the primary backend. Blocks on the database so call this from a blocking task
+    pub fn search(&self, search: &Search) -> Result<Vec<SearchResult>> {
+        self.backends.lock().unwrap()[self.primary].search(search)
+    }
+
+    /// Reads from the primary backend. Blocks on the database so call this from a blocking task
+    pub fn contacts(&self) -> Result<Vec<Contact>> {
+        self.backends.lock().unwrap()[self.primary].contacts()
+    }
+
+    /// Fails unless data sent to a peer refers to a message it may refer to, see `check_target`.
+    /// Blocks on the database so call this from a blocking task.
+    pub fn check_target(&self, pair: &PeerHostPair, data: &Data) -> Result<()> {
+        check_target(
+            self.backends.lock().unwrap()[self.primary].as_mut(),
+            pair,
+            data,
+            Direction::Outgoing,
+        )
+    }
+
+    /// Whether read receipts are exchanged with a peer, either way. Blocks on the database so
+    /// call this from a blocking task.
+    pub fn read_receipts(&self, pair: &PeerHostPair) -> Result<bool> {
+        let contact = self.backends.lock().unwrap()[self.primary].contact(pair)?;
+
+        Ok(self.read_receipts_for(contact.map(|x| x.metadata).as_ref()))
+    }
+
+    /// Whether read receipts are sent to a contact with this metadata, or to a peer that isn't
+    /// a contact
+    pub fn read_receipts_for(&self, metadata: Option<&ContactMetadata>) -> bool {
+        metadata
+            .and_then(|x| x.read_receipts)
+            .unwrap_or(self.read_receipts)
+    }
+
+    /// Queues data in every backend. Unlike packets this is written before returning, so a
+    /// connection that flushes the outbox right after can't miss it. Blocks on the database so
+    /// call this from a blocking task.
+    pub fn enqueue(&self, entry: &OutboxEntry) -> Result<()> {
+        for backend in self.backends.lock().unwrap().iter_mut() {
+            backend.enqueue(entry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads from the primary backend. Blocks on the database so call this from a blocking task
+    pub fn outbox(&self, pair: Option<&PeerHostPair>) -> Result<Vec<OutboxEntry>> {
+        self.backends.lock().unwrap()[self.primary].outbox(pair)
+    }
+
+    /// Reads from the primary backend. Blocks on the database so call this from a blocking task
+    pub fn outbox_pairs(&self) -> Result<Vec<PeerHostPair>> {
+        self.backends.lock().unwrap()[self.primary].outbox_pairs()
+    }
+
+    /// Reads from the primary backend. Blocks on the database so call this from a blocking task
+    pub fn identity_key(&self, pair: &PeerHostPair) -> Result<Option<Vec<u8>>> {
+        self.backends.lock().unwrap()[self.primary].identity_key(pair)
+    }
+
+    /// Pins a key in every backend before returning, like `enqueue`. Blocks on the database so
+    /// call this from a blocking task.
+    pub fn pin_identity_key(&self, pair: &PeerHostPair, key: &[u8]) -> Result<()> {
+        let timestamp = chrono::Utc::now().timestamp_millis();
+
+        for backend in self.backends.lock().unwrap().iter_mut() {
+            backend.pin_identity_key(pair, key, timestamp)?;
+        }
+
+        Ok(())
+    }
+
+    /// Collects one conversation, or all of them, from the primary backend
+    pub fn export(&self, pair: Option<&PeerHostPair>) -> Result<Archive> {
+        Archive::collect(self.backends.lock().unwrap()[self.primary].as_mut(), pair)
+    }
+
+    /// Merges an archive into every backend. Returns the number of contacts and messages the
+    /// primary backend didn't have.
+    pub fn import(&self, archive: &Archive) -> Result<(usize, usize)> {
+        let mut added = (0, 0);
+
+        for (i, backend) in self.backends.lock().unwrap().iter_mut().enumerate() {
+            let res = archive.merge(backend.as_mut())?;
+
+            if i == self.primary {
+                added = res;
+            }
+        }
+
+        Ok(added)
     }
 
     pub fn subscribe(&self) -> Receiver<ClientPacket> {
@@ -50,3 +575,491 @@ impl Storage {
         rx
     }
 }
+
+#[cfg(test)]
+pub fn test_backend(backend: &mut dyn StorageBackend) {
+    use crate::{config::Retention, connections::model::Envelope, types::PublicKey};
+
+    let pair = PeerHostPair {
+        peer_public_key: PublicKey::from_onion_address(
+            "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion",
+        )
+        .unwrap(),
+        host_public_key: PublicKey::from_onion_address(
+            "sp3k262uwy4r2k3ycr5awluarykdpag6a7y33jxop4cs2lu5uz5sseqd.onion",
+        )
+        .unwrap(),
+    };
+
+    backend.migrate().unwrap();
+
+    for i in 0..5 {
+        backend
+            .insert(&NewMessage {
+                pair: pair.clone(),
+                direction: Direction::Incoming,
+                timestamp: i,
+                message_id: None,
+                sent_at: None,
+                data: Data::Message(i.to_string()),
+            })
+            .unwrap();
+    }
+
+    // A resent envelope is only stored once
+    let resent = NewMessage {
+        pair: pair.clone(),
+        direction: Direction::Incoming,
+        timestamp: 5,
+        message_id: Some(MessageId::random()),
+        sent_at: Some(5),
+        data: Data::Message("5".to_string()),
+    };
+
+    backend.insert(&resent).unwrap();
+    backend.insert(&resent).unwrap();
+
+    // Deleting a message takes its edits along
+    let target_id = MessageId::random();
+
+    for data in [
+        Data::Message("typo".to_string()),
+        Data::Edit {
+            target_id,
+            new_body: "fixed".to_string(),
+        },
+    ] {
+        backend
+            .insert(&NewMessage {
+                pair: pair.clone(),
+                direction: Direction::Outgoing,
+                timestamp: 6,
+                message_id: Some(match data {
+                    Data::Message(_) => target_id,
+                    _ => MessageId::random(),
+                }),
+                sent_at: Some(6),
+                data,
+            })
+            .unwrap();
+    }
+
+    assert_eq!(
+        backend
+            .message(&pair, &target_id, Direction::Outgoing)
+            .unwrap()
+            .unwrap()
+            .data
+            .text(),
+        Some("typo")
+    );
+
+    // The peer may reuse the ID without touching our message
+    let reused = NewMessage {
+        pair: pair.clone(),
+        direction: Direction::Incoming,
+        timestamp: 6,
+        message_id: Some(target_id),
+        sent_at: Some(6),
+        data: Data::Message("mine now".to_string()),
+    };
+
+    backend.insert(&reused).unwrap();
+    backend
+        .react(&pair, &target_id, Direction::Outgoing, "🎉", false)
+        .unwrap();
+    assert_eq!(
+        backend
+            .message(&pair, &target_id, Direction::Incoming)
+            .unwrap()
+            .unwrap()
+            .data
+            .text(),
+        Some("mine now")
+    );
+    assert_eq!(
+        backend
+            .delete_message(&pair, &target_id, Direction::Incoming)
+            .unwrap(),
+        1
+    );
+    assert!(backend
+        .message(&pair, &target_id, Direction::Outgoing)
+        .unwrap()
+        .is_some());
+    assert!(backend
+        .reactions(&pair, &[target_id])
+        .unwrap()
+        .contains_key(&target_id));
+    backend
+        .react(&pair, &target_id, Direction::Outgoing, "🎉", true)
+        .unwrap();
+
+    for (direction, emoji, remove) in [
+        (Direction::Incoming, "👍", false),
+        (Direction::Incoming, "👍", false),
+        (Direction::Outgoing, "🎉", false),
+        (Direction::Incoming, "❤️", false),
+        (Direction::Incoming, "👍", true),
+    ] {
+        backend
+            .react(&pair, &target_id, direction, emoji, remove)
+            .unwrap();
+    }
+
+    let reactions = |backend: &mut dyn StorageBackend| {
+        backend
+            .reactions(&pair, &[target_id, MessageId::random()])
+            .unwrap()
+            .remove(&target_id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|x| (x.direction, x.emoji))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        reactions(backend),
+        [
+            (Direction::Incoming, "❤️".to_string()),
+            (Direction::Outgoing, "🎉".to_string())
+        ]
+    );
+    assert_eq!(
+        backend
+            .delete_message(&pair, &target_id, Direction::Outgoing)
+            .unwrap(),
+        2
+    );
+    assert!(backend
+        .message(&pair, &target_id, Direction::Outgoing)
+        .unwrap()
+        .is_none());
+    assert!(reactions(backend).is_empty());
+
+    let mut fetch = |before, after| {
+        backend
+            .query(&FetchHistory {
+                pair: pair.clone(),
+                before,
+                after,
+                limit: 2,
+            })
+            .unwrap()
+            .into_iter()
+            .map(|x| x.timestamp)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(fetch(None, None), [4, 5]);
+    assert_eq!(fetch(Some(4), None), [1, 2]);
+    assert_eq!(fetch(None, Some(1)), [1, 2]);
+    assert_eq!(fetch(Some(2), Some(0)), [0]);
+
+    let mut search = |query: &str, since| {
+        backend
+            .search(&Search {
+                query: query.to_string(),
+                host_public_key: Some(pair.host_public_key),
+                peer_public_key: None,
+                since,
+                until: None,
+                limit: 10,
+            })
+            .unwrap()
+            .into_iter()
+            .map(|x| x.message.timestamp)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(search("3", None), [3]);
+    assert!(search("3", Some(4)).is_empty());
+    assert!(search("3 4", None).is_empty());
+    assert!(search("\"", None).is_empty());
+    assert!(search("", None).is_empty());
+
+    assert_eq!(
+        backend.conversations().unwrap(),
+        std::slice::from_ref(&pair)
+    );
+    assert_eq!(backend.truncate(&pair, 5).unwrap(), 1);
+    assert_eq!(backend.truncate(&pair, 5).unwrap(), 0);
+
+    // Reactions go away with their message
+    let resent_id = resent.message_id.unwrap();
+    backend
+        .react(&pair, &resent_id, Direction::Incoming, "👍", false)
+        .unwrap();
+
+    let delete = |backend: &mut dyn StorageBackend, until| {
+        backend
+            .delete(&DeleteHistory {
+                pair: pair.clone(),
+                until,
+            })
+            .unwrap()
+    };
+    let reacted = |backend: &mut dyn StorageBackend| {
+        backend
+            .reactions(&pair, &[resent_id])
+            .unwrap()
+            .contains_key(&resent_id)
+    };
+
+    assert_eq!(delete(backend, Some(1)), 1);
+    assert!(reacted(backend));
+    assert_eq!(delete(backend, None), 4);
+    assert!(!reacted(backend));
+
+    assert!(matches!(
+        backend.rename_contact(&pair, "alice"),
+        Err(BlackedoutError::ContactDoesNotExist)
+    ));
+
+    backend.touch_contact(&pair, 10).unwrap();
+    backend
+        .add_contact(
+            &AddContact {
+                pair: pair.clone(),
+                metadata: ContactMetadata {
+                    nickname: "bob".to_string(),
+                    notes: "met at the library".to_string(),
+                    tags: vec!["friends".to_string()],
+                    retention: Some(Retention::Days { days: 7 }),
+                    read_receipts: Some(false),
+                },
+            },
+            20,
+        )
+        .unwrap();
+    backend.rename_contact(&pair, "alice").unwrap();
+    backend.touch_contact(&pair, 30).unwrap();
+
+    let contacts = backend.contacts().unwrap();
+    assert_eq!(contacts.len(), 1);
+    assert_eq!(contacts[0].pair, pair);
+    assert_eq!(contacts[0].metadata.nickname, "alice");
+    assert_eq!(contacts[0].metadata.tags, ["friends"]);
+    assert_eq!(
+        contacts[0].metadata.retention,
+        Some(Retention::Days { days: 7 })
+    );
+    assert_eq!(
+        backend
+            .contact(&pair)
+            .unwrap()
+            .unwrap()
+            .metadata
+            .read_receipts,
+        Some(false)
+    );
+    assert_eq!(
+        (contacts[0].first_seen, contacts[0].last_seen),
+        (10, Some(30))
+    );
+
+    assert_eq!(backend.remove_contact(&pair).unwrap(), 1);
+    assert!(backend.contacts().unwrap().is_empty());
+
+    let entries = (0..3)
+        .map(|i| OutboxEntry {
+            token: [i; 12],
+            pair: pair.clone(),
+            envelope: Envelope::new(Data::Message(i.to_string())),
+        })
+        .collect::<Vec<_>>();
+
+    for entry in &entries {
+        backend.enqueue(entry).unwrap();
+    }
+
+    assert_eq!(backend.dequeue(&entries[1].envelope.message_id).unwrap(), 1);
+    assert_eq!(backend.dequeue(&entries[1].envelope.message_id).unwrap(), 0);
+
+    let outbox = backend.outbox(Some(&pair)).unwrap();
+    assert_eq!(
+        outbox.iter().map(|x| x.token[0]).collect::<Vec<_>>(),
+        [0, 2]
+    );
+    assert_eq!(
+        outbox[1].envelope.message_id,
+        entries[2].envelope.message_id
+    );
+    assert_eq!(outbox[1].envelope.data.text(), Some("2"));
+    assert_eq!(backend.outbox(None).unwrap().len(), 2);
+    assert_eq!(backend.outbox_pairs().unwrap(), std::slice::from_ref(&pair));
+
+    assert_eq!(backend.identity_key(&pair).unwrap(), None);
+    backend.pin_identity_key(&pair, &[1; 8], 40).unwrap();
+    backend.pin_identity_key(&pair, &[2; 8], 50).unwrap();
+    assert_eq!(backend.identity_key(&pair).unwrap(), Some(vec![1; 8]));
+    assert_eq!(backend.unpin_identity_key(&pair).unwrap(), 1);
+    backend.pin_identity_key(&pair, &[2; 8], 60).unwrap();
+    assert_eq!(backend.identity_key(&pair).unwrap(), Some(vec![2; 8]));
+}
+
+#[test]
+fn edit_permissions() {
+    use crate::{storage::memory::Memory, types::PublicKey};
+
+    let pair = PeerHostPair {
+        peer_public_key: PublicKey::from_onion_address(
+            "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion",
+        )
+        .unwrap(),
+        host_public_key: PublicKey::from_onion_address(
+            "sp3k262uwy4r2k3ycr5awluarykdpag6a7y33jxop4cs2lu5uz5sseqd.onion",
+        )
+        .unwrap(),
+    };
+
+    let mut backend = Memory::default();
+    let theirs = Envelope::new(Data::Message("hi".to_string()));
+    let ours = Envelope::new(Data::Message("hello".to_string()));
+
+    store(
+        &mut backend,
+        &ClientPacket::DataReceived {
+            pair: pair.clone(),
+            envelope: theirs.clone(),
+        },
+        0,
+    )
+    .unwrap();
+    backend
+        .enqueue(&OutboxEntry {
+            token: [0; 12],
+            pair: pair.clone(),
+            envelope: ours.clone(),
+        })
+        .unwrap();
+
+    let edit = |target: &Envelope| Data::Edit {
+        target_id: target.message_id,
+        new_body: "edited".to_string(),
+    };
+    let mut check = |data: &Data, direction| check_target(&mut backend, &pair, data, direction);
+
+    assert!(check(&edit(&theirs), Direction::Incoming).is_ok());
+    assert!(check(&edit(&ours), Direction::Outgoing).is_ok());
+    assert!(check(
+        &Data::Reaction {
+            target_id: theirs.message_id,
+            emoji: "👍".to_string(),
+            remove: false,
+        },
+        Direction::Outgoing
+    )
+    .is_ok());
+    assert!(matches!(
+        check(&edit(&theirs), Direction::Outgoing),
+        Err(BlackedoutError::NotMessageSender)
+    ));
+
+    let react = |emoji: &str| Data::Reaction {
+        target_id: theirs.message_id,
+        emoji: emoji.to_string(),
+        remove: false,
+    };
+
+    assert!(matches!(
+        check(&react(""), Direction::Incoming),
+        Err(BlackedoutError::InvalidReaction)
+    ));
+    assert!(matches!(
+        check(&react(&"👍".repeat(9)), Direction::Incoming),
+        Err(BlackedoutError::InvalidReaction)
+    ));
+    assert!(matches!(
+        check(&edit(&ours), Direction::Incoming),
+        Err(BlackedoutError::NotMessageSender)
+    ));
+
+    for i in 0..MAX_REACTIONS {
+        backend
+            .react(
+                &pair,
+                &theirs.message_id,
+                Direction::Incoming,
+                &i.to_string(),
+                false,
+            )
+            .unwrap();
+    }
+
+    assert!(check_target(&mut backend, &pair, &react("0"), Direction::Incoming).is_ok());
+    assert!(check_target(&mut backend, &pair, &react("👍"), Direction::Outgoing).is_ok());
+    assert!(matches!(
+        check_target(&mut backend, &pair, &react("👍"), Direction::Incoming),
+        Err(BlackedoutError::InvalidReaction)
+    ));
+
+    // A peer storing a message under one of our IDs only gets to delete its own
+    let reused = Envelope {
+        message_id: ours.message_id,
+        ..Envelope::new(Data::Message("mine now".to_string()))
+    };
+    let delete_ours = Envelope::new(Data::Delete {
+        target_id: ours.message_id,
+    });
+
+    for envelope in [reused, delete_ours] {
+        store(
+            &mut backend,
+            &ClientPacket::DataReceived {
+                pair: pair.clone(),
+                envelope,
+            },
+            1,
+        )
+        .unwrap();
+    }
+
+    assert!(check_target(&mut backend, &pair, &edit(&ours), Direction::Outgoing).is_ok());
+    store(
+        &mut backend,
+        &ClientPacket::SendDataConfirmation {
+            token: [0; 12],
+            pair: pair.clone(),
+            envelope: ours.clone(),
+        },
+        2,
+    )
+    .unwrap();
+    assert!(backend
+        .message(&pair, &ours.message_id, Direction::Outgoing)
+        .unwrap()
+        .is_some());
+    assert!(backend
+        .message(&pair, &ours.message_id, Direction::Incoming)
+        .unwrap()
+        .is_none());
+
+    let delete = Envelope::new(Data::Delete {
+        target_id: theirs.message_id,
+    });
+
+    store(
+        &mut backend,
+        &ClientPacket::DataReceived {
+            pair: pair.clone(),
+            envelope: delete.clone(),
+        },
+        1,
+    )
+    .unwrap();
+
+    // Only the tombstone is left and it can't be deleted again
+    assert!(backend
+        .message(&pair, &theirs.message_id, Direction::Incoming)
+        .unwrap()
+        .is_none());
+    assert!(backend
+        .message(&pair, &delete.message_id, Direction::Incoming)
+        .unwrap()
+        .is_some());
+    assert!(matches!(
+        check_target(&mut backend, &pair, &edit(&delete), Direction::Incoming),
+        Err(BlackedoutError::MessageDoesNotExist)
+    ));
+}
@@ -0,0 +1,325 @@
+#![allow(non_local_definitions)]
+
+use serde::{Deserialize, Serialize};
+use serde_with::{base64::Base64, serde_as};
+
+use crate::{
+    client::model::{ClientPacket, PeerHostPair},
+    config::Retention,
+    connections::model::{Data, Envelope},
+    error::{BlackedoutError, Result},
+    types::{MessageId, PublicKey},
+};
+
+use super::schema::messages;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Incoming = 0,
+    Outgoing = 1,
+}
+
+impl Direction {
+    pub fn other(self) -> Self {
+        match self {
+            Direction::Incoming => Direction::Outgoing,
+            Direction::Outgoing => Direction::Incoming,
+        }
+    }
+}
+
+impl TryFrom<i32> for Direction {
+    type Error = BlackedoutError;
+
+    fn try_from(value: i32) -> Result<Self> {
+        match value {
+            0 => Ok(Direction::Incoming),
+            1 => Ok(Direction::Outgoing),
+            _ => Err(BlackedoutError::CorruptedRecord),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Message {
+    pub id: i64,
+    #[serde(flatten)]
+    pub pair: PeerHostPair,
+    pub direction: Direction,
+    pub timestamp: i64,
+    /// Missing on messages stored before envelopes existed
+    pub message_id: Option<MessageId>,
+    pub sent_at: Option<i64>,
+    pub data: Data,
+    /// Only filled in by history queries
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+}
+
+/// An emoji one side reacted to a message with. Incoming ones are the peer's.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Reaction {
+    pub emoji: String,
+    pub direction: Direction,
+}
+
+/// The emojis one side reacted to a message with, as stored
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Emojis {
+    pub emojis: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NewMessage {
+    #[serde(flatten)]
+    pub pair: PeerHostPair,
+    pub direction: Direction,
+    pub timestamp: i64,
+    #[serde(default)]
+    pub message_id: Option<MessageId>,
+    #[serde(default)]
+    pub sent_at: Option<i64>,
+    pub data: Data,
+}
+
+#[derive(Insertable)]
+#[table_name = "messages"]
+pub struct NewMessageRow {
+    pub host_public_key: Vec<u8>,
+    pub peer_public_key: Vec<u8>,
+    pub direction: i32,
+    pub timestamp: i64,
+    pub data: Vec<u8>,
+    pub message_id: Option<Vec<u8>>,
+    pub sent_at: Option<i64>,
+    pub target_id: Option<Vec<u8>>,
+}
+
+impl NewMessage {
+    /// Returns the message that should be stored for a packet, if any
+    pub fn from_packet(packet: &ClientPacket, timestamp: i64) -> Option<Self> {
+        let (pair, direction, envelope) = match packet {
+            ClientPacket::DataReceived { pair, envelope } => (pair, Direction::Incoming, envelope),
+            ClientPacket::SendDataConfirmation { pair, envelope, .. } => {
+                (pair, Direction::Outgoing, envelope)
+            }
+            _ => return None,
+        };
+
+        if !envelope.data.stored() {
+            return None;
+        }
+
+        Some(NewMessage {
+            pair: pair.clone(),
+            direction,
+            timestamp,
+            message_id: Some(envelope.message_id),
+            sent_at: Some(envelope.sent_at),
+            data: envelope.data.clone(),
+        })
+    }
+
+    pub fn to_row(&self) -> Result<NewMessageRow> {
+        Ok(NewMessageRow {
+            host_public_key: self.pair.host_public_key.as_bytes().to_vec(),
+            peer_public_key: self.pair.peer_public_key.as_bytes().to_vec(),
+            direction: self.direction as i32,
+            timestamp: self.timestamp,
+            data: bson::to_vec(&self.data)?,
+            message_id: self.message_id.map(|x| x.as_bytes().to_vec()),
+            sent_at: self.sent_at,
+            target_id: self.data.target().map(|x| x.as_bytes().to_vec()),
+        })
+    }
+}
+
+impl From<Message> for NewMessage {
+    fn from(message: Message) -> Self {
+        NewMessage {
+            pair: message.pair,
+            direction: message.direction,
+            timestamp: message.timestamp,
+            message_id: message.message_id,
+            sent_at: message.sent_at,
+            data: message.data,
+        }
+    }
+}
+
+#[derive(Queryable)]
+pub struct MessageRow {
+    pub id: i64,
+    pub host_public_key: Vec<u8>,
+    pub peer_public_key: Vec<u8>,
+    pub direction: i32,
+    pub timestamp: i64,
+    pub data: Vec<u8>,
+    pub message_id: Option<Vec<u8>>,
+    pub sent_at: Option<i64>,
+    /// Only used in queries, the same ID is in `data`
+    pub _target_id: Option<Vec<u8>>,
+}
+
+impl TryFrom<MessageRow> for Message {
+    type Error = BlackedoutError;
+
+    fn try_from(row: MessageRow) -> Result<Self> {
+        Ok(Message {
+            id: row.id,
+            pair: PeerHostPair {
+                peer_public_key: PublicKey::from_bytes(&row.peer_public_key)?,
+                host_public_key: PublicKey::from_bytes(&row.host_public_key)?,
+            },
+            direction: row.direction.try_into()?,
+            timestamp: row.timestamp,
+            message_id: row
+                .message_id
+                .map(|x| MessageId::from_bytes(&x))
+                .transpose()?,
+            sent_at: row.sent_at,
+            data: bson::from_slice(&row.data)?,
+            reactions: Vec::new(),
+        })
+    }
+}
+
+/// Everything about a contact that is encrypted at rest
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ContactMetadata {
+    #[serde(default)]
+    pub nickname: String,
+    #[serde(default)]
+    pub notes: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Overrides the retention of the host address and the global one
+    #[serde(default)]
+    pub retention: Option<Retention>,
+    /// Overrides the global read receipts setting
+    #[serde(default)]
+    pub read_receipts: Option<bool>,
+}
+
+/// A peer known to one of the host identities. `first_seen` is when the contact was added or
+/// first connected and `last_seen` the last time a packet was exchanged with it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Contact {
+    #[serde(flatten)]
+    pub pair: PeerHostPair,
+    #[serde(flatten)]
+    pub metadata: ContactMetadata,
+    pub first_seen: i64,
+    pub last_seen: Option<i64>,
+}
+
+#[derive(Queryable)]
+pub struct ContactRow {
+    pub host_public_key: Vec<u8>,
+    pub peer_public_key: Vec<u8>,
+    pub metadata: Vec<u8>,
+    pub first_seen: i64,
+    pub last_seen: Option<i64>,
+}
+
+impl TryFrom<ContactRow> for Contact {
+    type Error = BlackedoutError;
+
+    fn try_from(row: ContactRow) -> Result<Self> {
+        Ok(Contact {
+            pair: PeerHostPair {
+                peer_public_key: PublicKey::from_bytes(&row.peer_public_key)?,
+                host_public_key: PublicKey::from_bytes(&row.host_public_key)?,
+            },
+            metadata: bson::from_slice(&row.metadata)?,
+            first_seen: row.first_seen,
+            last_seen: row.last_seen,
+        })
+    }
+}
+
+/// Data waiting in the outbox until the peer acknowledges it. `token` is the one the client
+/// sent it with, so it can match the `send_data_confirmation` to the pending message.
+#[serde_as]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OutboxEntry {
+    #[serde_as(as = "Base64")]
+    pub token: [u8; 12],
+    #[serde(flatten)]
+    pub pair: PeerHostPair,
+    #[serde(flatten)]
+    pub envelope: Envelope,
+}
+
+#[derive(Queryable)]
+pub struct OutboxRow {
+    pub host_public_key: Vec<u8>,
+    pub peer_public_key: Vec<u8>,
+    pub token: Vec<u8>,
+    pub message_id: Vec<u8>,
+    pub sent_at: i64,
+    pub data: Vec<u8>,
+}
+
+impl TryFrom<OutboxRow> for OutboxEntry {
+    type Error = BlackedoutError;
+
+    fn try_from(row: OutboxRow) -> Result<Self> {
+        Ok(OutboxEntry {
+            token: row
+                .token
+                .try_into()
+                .map_err(|_| BlackedoutError::CorruptedRecord)?,
+            pair: PeerHostPair {
+                peer_public_key: PublicKey::from_bytes(&row.peer_public_key)?,
+                host_public_key: PublicKey::from_bytes(&row.host_public_key)?,
+            },
+            envelope: Envelope {
+                message_id: MessageId::from_bytes(&row.message_id)?,
+                sent_at: row.sent_at,
+                data: bson::from_slice(&row.data)?,
+            },
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct SnippetPart {
+    pub text: String,
+    pub highlight: bool,
+}
+
+/// A message row returned by SQLite's full-text search along with its snippet
+#[cfg(feature = "storage-sqlite")]
+#[derive(QueryableByName)]
+pub struct SearchRow {
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub id: i64,
+    #[sql_type = "diesel::sql_types::Binary"]
+    pub host_public_key: Vec<u8>,
+    #[sql_type = "diesel::sql_types::Binary"]
+    pub peer_public_key: Vec<u8>,
+    #[sql_type = "diesel::sql_types::Integer"]
+    pub direction: i32,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub timestamp: i64,
+    #[sql_type = "diesel::sql_types::Binary"]
+    pub data: Vec<u8>,
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Binary>"]
+    pub message_id: Option<Vec<u8>>,
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::BigInt>"]
+    pub sent_at: Option<i64>,
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Binary>"]
+    pub target_id: Option<Vec<u8>>,
+    #[sql_type = "diesel::sql_types::Text"]
+    pub snippet: String,
+}
+
+/// A message matching a search, with the part of its body that matched
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    pub message: Message,
+    pub snippet: Vec<SnippetPart>,
+}
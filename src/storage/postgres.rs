@@ -0,0 +1,95 @@
+use diesel::{connection::SimpleConnection, pg::PgConnection, Connection};
+
+use crate::{client::model::Search, error::Result};
+
+use super::{
+    cipher::Cipher,
+    migrations::{embed_migration, EmbeddedMigration},
+    model::{NewMessage, SearchResult},
+    sql::impl_sql_backend,
+};
+
+const MIGRATIONS: &[EmbeddedMigration] = &[
+    embed_migration!("postgres", "20261018000000", "create_messages"),
+    embed_migration!("postgres", "20261018000001", "create_contacts"),
+    embed_migration!("postgres", "20261018000003", "add_message_envelopes"),
+    embed_migration!("postgres", "20261018000004", "create_outbox"),
+    embed_migration!("postgres", "20261018000005", "add_message_targets"),
+    embed_migration!("postgres", "20261018000006", "create_reactions"),
+    embed_migration!("postgres", "20261018000007", "create_identity_keys"),
+];
+
+pub struct Postgres {
+    conn: PgConnection,
+    cipher: Option<Cipher>,
+}
+
+impl Postgres {
+    pub fn open(url: &str) -> Result<Self> {
+        Ok(Postgres {
+            conn: PgConnection::establish(url)?,
+            cipher: None,
+        })
+    }
+
+    /// Copies every table into a `backup_<version>` schema in the same database
+    fn backup(&self, version: &str) -> Result<()> {
+        self.conn
+            .batch_execute(&format!(
+                "
+DO $$
+DECLARE
+    name TEXT;
+BEGIN
+    CREATE SCHEMA backup_{0};
+
+    FOR name IN SELECT tablename FROM pg_tables WHERE schemaname = current_schema() LOOP
+        EXECUTE format('CREATE TABLE backup_{0}.%I AS TABLE %I', name, name);
+    END LOOP;
+END
+$$;
+",
+                version
+            ))
+            .map_err(Into::into)
+    }
+
+    /// Rewrites every table rows are deleted from so they don't linger in their files
+    fn vacuum(&self) -> Result<()> {
+        self.conn
+            .batch_execute("VACUUM FULL messages, contacts, reactions, outbox, identity_keys")
+            .map_err(Into::into)
+    }
+
+    // There is no full-text index so searches scan the decrypted messages
+    fn index(&self, _message: &NewMessage) -> Result<()> {
+        Ok(())
+    }
+
+    fn rebuild_index(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn search_index(
+        &self,
+        _search: &Search,
+        _terms: &[String],
+    ) -> Result<Option<Vec<SearchResult>>> {
+        Ok(None)
+    }
+}
+
+impl_sql_backend!(Postgres);
+
+/// Runs against the database in `BLACKEDOUT_TEST_POSTGRES_URL` inside a transaction that is
+/// never committed. Run it with `cargo test --features storage-postgres -- --ignored`.
+#[test]
+#[ignore = "needs a PostgreSQL database in BLACKEDOUT_TEST_POSTGRES_URL"]
+fn postgres_backend() {
+    let url = std::env::var("BLACKEDOUT_TEST_POSTGRES_URL")
+        .expect("BLACKEDOUT_TEST_POSTGRES_URL is not set");
+
+    let mut postgres = Postgres::open(&url).unwrap();
+    postgres.conn.begin_test_transaction().unwrap();
+    super::test_backend(&mut postgres);
+}
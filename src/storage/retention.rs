@@ -0,0 +1,169 @@
+use std::{collections::HashMap, thread, time::Duration};
+
+use crate::{
+    client::model::{DeleteHistory, PeerHostPair},
+    config::{Address, Retention},
+    error::Result,
+    tor::onion::read_public_key,
+    types::PublicKey,
+};
+
+use super::{model::Contact, Backends, StorageBackend};
+
+/// How often the policies are enforced after the first time at startup
+const INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// Retention of every conversation: the contact's policy, else its host address', else the
+/// global one
+pub struct Policies {
+    pub global: Retention,
+    pub addresses: HashMap<PublicKey, Retention>,
+}
+
+impl Policies {
+    /// Fails when an address with a policy has no hostname yet rather than silently applying the
+    /// global policy to its conversations
+    pub fn load(addresses: &[Address], global: Retention) -> Result<Self> {
+        Ok(Policies {
+            global,
+            addresses: addresses
+                .iter()
+                .filter_map(|x| x.retention.map(|retention| (&x.name, retention)))
+                .map(|(name, retention)| Ok((read_public_key(name)?, retention)))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    fn resolve(&self, pair: &PeerHostPair, contacts: &[Contact]) -> Retention {
+        contacts
+            .iter()
+            .find(|x| x.pair == *pair)
+            .and_then(|x| x.metadata.retention)
+            .or_else(|| self.addresses.get(&pair.host_public_key).copied())
+            .unwrap_or(self.global)
+    }
+}
+
+/// Deletes the messages the policies no longer keep and compacts the backend so they can't be
+/// recovered from disk. Returns the number of messages deleted.
+pub fn enforce(backend: &mut dyn StorageBackend, policies: &Policies, now: i64) -> Result<usize> {
+    let contacts = backend.contacts()?;
+    let mut deleted = 0;
+
+    for pair in backend.conversations()? {
+        deleted += match policies.resolve(&pair, &contacts) {
+            Retention::Forever => 0,
+            Retention::Days { days } => backend.delete(&DeleteHistory {
+                pair,
+                until: Some(now - days as i64 * DAY),
+            })?,
+            Retention::Messages { count } => backend.truncate(&pair, count as usize)?,
+        };
+    }
+
+    if deleted > 0 {
+        backend.compact()?;
+    }
+
+    Ok(deleted)
+}
+
+/// Enforces the policies on every backend in a background thread
+pub fn spawn(backends: Backends, addresses: Vec<Address>, global: Retention) {
+    thread::spawn(move || loop {
+        match Policies::load(&addresses, global) {
+            Ok(policies) => {
+                let now = chrono::Utc::now().timestamp_millis();
+
+                for backend in backends.lock().unwrap().iter_mut() {
+                    if let Err(e) = enforce(backend.as_mut(), &policies, now) {
+                        println!("Error enforcing retention: {:?}", e);
+                    }
+                }
+            }
+            Err(e) => println!("Skipping retention, host addresses unavailable: {:?}", e),
+        }
+
+        thread::sleep(INTERVAL);
+    });
+}
+
+#[test]
+fn enforce_policies() {
+    use crate::{
+        client::model::AddContact,
+        connections::model::Data,
+        storage::{
+            memory::Memory,
+            model::{ContactMetadata, Direction, NewMessage},
+        },
+    };
+
+    let keys = [
+        "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion",
+        "sp3k262uwy4r2k3ycr5awluarykdpag6a7y33jxop4cs2lu5uz5sseqd.onion",
+    ]
+    .map(|x| PublicKey::from_onion_address(x).unwrap());
+
+    // Every host and peer combination, one of them with a contact that keeps a single message
+    let pairs = [(0, 0), (0, 1), (1, 0), (1, 1)].map(|(host, peer)| PeerHostPair {
+        host_public_key: keys[host],
+        peer_public_key: keys[peer],
+    });
+
+    let mut backend = Memory::default();
+
+    for pair in &pairs {
+        for day in 0..5 {
+            backend
+                .insert(&NewMessage {
+                    pair: pair.clone(),
+                    direction: Direction::Incoming,
+                    timestamp: day * DAY,
+                    message_id: None,
+                    sent_at: None,
+                    data: Data::Message(day.to_string()),
+                })
+                .unwrap();
+        }
+    }
+
+    backend
+        .add_contact(
+            &AddContact {
+                pair: pairs[1].clone(),
+                metadata: ContactMetadata {
+                    retention: Some(Retention::Messages { count: 1 }),
+                    ..Default::default()
+                },
+            },
+            0,
+        )
+        .unwrap();
+
+    let policies = Policies {
+        global: Retention::Days { days: 3 },
+        addresses: HashMap::from([(keys[0], Retention::Days { days: 2 })]),
+    };
+
+    assert_eq!(
+        enforce(&mut backend, &policies, 4 * DAY).unwrap(),
+        3 + 4 + 2 + 2
+    );
+
+    let kept = pairs.map(|pair| {
+        backend
+            .query(&crate::client::model::FetchHistory {
+                pair,
+                before: None,
+                after: None,
+                limit: 10,
+            })
+            .unwrap()
+            .len()
+    });
+
+    assert_eq!(kept, [2, 1, 3, 3]);
+}
@@ -0,0 +1,72 @@
+#![allow(non_local_definitions)]
+
+table! {
+    messages (id) {
+        id -> BigInt,
+        host_public_key -> Binary,
+        peer_public_key -> Binary,
+        direction -> Integer,
+        timestamp -> BigInt,
+        data -> Binary,
+        message_id -> Nullable<Binary>,
+        sent_at -> Nullable<BigInt>,
+        target_id -> Nullable<Binary>,
+    }
+}
+
+table! {
+    encryption (id) {
+        id -> Integer,
+        salt -> Binary,
+        key -> Binary,
+    }
+}
+
+table! {
+    contacts (host_public_key, peer_public_key) {
+        host_public_key -> Binary,
+        peer_public_key -> Binary,
+        metadata -> Binary,
+        first_seen -> BigInt,
+        last_seen -> Nullable<BigInt>,
+    }
+}
+
+table! {
+    outbox (id) {
+        id -> BigInt,
+        host_public_key -> Binary,
+        peer_public_key -> Binary,
+        token -> Binary,
+        message_id -> Binary,
+        sent_at -> BigInt,
+        data -> Binary,
+    }
+}
+
+table! {
+    reactions (host_public_key, peer_public_key, target_id, direction) {
+        host_public_key -> Binary,
+        peer_public_key -> Binary,
+        target_id -> Binary,
+        direction -> Integer,
+        emojis -> Binary,
+    }
+}
+
+table! {
+    identity_keys (host_public_key, peer_public_key) {
+        host_public_key -> Binary,
+        peer_public_key -> Binary,
+        kyber_public_key -> Binary,
+        pinned_at -> BigInt,
+    }
+}
+
+// SQLite only, FTS5 index of message bodies
+table! {
+    messages_fts (rowid) {
+        rowid -> BigInt,
+        body -> Text,
+    }
+}
@@ -0,0 +1,169 @@
+use crate::connections::model::Data;
+
+use super::model::SnippetPart;
+
+/// Characters around the first match kept in snippets of long messages
+const SNIPPET_CONTEXT: usize = 48;
+
+/// Marks highlighted text in snippets returned by SQLite's `snippet()`
+#[cfg(feature = "storage-sqlite")]
+pub const HIGHLIGHT_START: char = '\u{1}';
+#[cfg(feature = "storage-sqlite")]
+pub const HIGHLIGHT_END: char = '\u{2}';
+
+/// Splits a query into lowercase words, all of which have to match the start of a word in the
+/// body. Both the full-text index and the scan below match this way.
+pub fn terms(query: &str) -> Vec<String> {
+    query.split_whitespace().map(str::to_lowercase).collect()
+}
+
+/// Builds an FTS5 query matching every term as a prefix. Quoting keeps operators typed by the
+/// user from being interpreted.
+#[cfg(feature = "storage-sqlite")]
+pub fn fts_query(terms: &[String]) -> String {
+    terms
+        .iter()
+        .map(|x| format!("\"{}\"*", x.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Splits a snippet produced with `HIGHLIGHT_START` and `HIGHLIGHT_END` into parts
+#[cfg(feature = "storage-sqlite")]
+pub fn parse_snippet(snippet: &str) -> Vec<SnippetPart> {
+    let mut parts = Vec::new();
+    let mut rest = snippet;
+
+    while let Some(start) = rest.find(HIGHLIGHT_START) {
+        let end = rest[start..]
+            .find(HIGHLIGHT_END)
+            .map_or(rest.len(), |x| start + x);
+
+        push(&mut parts, &rest[..start], false);
+        push(&mut parts, &rest[start + 1..end], true);
+        rest = rest.get(end + 1..).unwrap_or_default();
+    }
+
+    push(&mut parts, rest, false);
+    parts
+}
+
+/// Matches a message for backends without a full-text index. Returns the snippet when the body
+/// contains every term, ignoring case.
+pub fn snippet(data: &Data, terms: &[String]) -> Option<Vec<SnippetPart>> {
+    let body = data.text()?;
+
+    let mut matches = Vec::new();
+
+    for term in terms {
+        let found = find_all(body, term);
+
+        if found.is_empty() {
+            return None;
+        }
+
+        matches.extend(found);
+    }
+
+    matches.sort_unstable();
+
+    // Keep some context around the first match instead of the whole message
+    let from = floor_char_boundary(body, matches[0].0.saturating_sub(SNIPPET_CONTEXT));
+    let to = floor_char_boundary(body, matches[0].1 + SNIPPET_CONTEXT);
+
+    let mut parts = Vec::new();
+    let mut position = from;
+
+    if from > 0 {
+        push(&mut parts, "…", false);
+    }
+
+    for (start, end) in matches {
+        if start < position || end > to {
+            continue;
+        }
+
+        push(&mut parts, &body[position..start], false);
+        push(&mut parts, &body[start..end], true);
+        position = end;
+    }
+
+    push(&mut parts, &body[position..to], false);
+
+    if to < body.len() {
+        push(&mut parts, "…", false);
+    }
+
+    Some(parts)
+}
+
+/// Byte ranges of every case-insensitive occurrence of a lowercase term at the start of a word,
+/// like the prefix queries of the full-text index
+fn find_all(body: &str, term: &str) -> Vec<(usize, usize)> {
+    body.char_indices()
+        .filter(|(start, _)| {
+            body[..*start]
+                .chars()
+                .next_back()
+                .is_none_or(|x| !x.is_alphanumeric())
+        })
+        .filter_map(|(start, _)| {
+            let mut needle = term.chars();
+            let mut end = start;
+
+            for c in body[start..].chars() {
+                for lower in c.to_lowercase() {
+                    match needle.next() {
+                        Some(x) if x == lower => (),
+                        _ => return None,
+                    }
+                }
+
+                end += c.len_utf8();
+
+                if needle.as_str().is_empty() {
+                    return Some((start, end));
+                }
+            }
+
+            None
+        })
+        .collect()
+}
+
+fn floor_char_boundary(body: &str, mut index: usize) -> usize {
+    index = index.min(body.len());
+
+    while !body.is_char_boundary(index) {
+        index -= 1;
+    }
+
+    index
+}
+
+fn push(parts: &mut Vec<SnippetPart>, text: &str, highlight: bool) {
+    if !text.is_empty() {
+        parts.push(SnippetPart {
+            text: text.to_string(),
+            highlight,
+        });
+    }
+}
+
+#[test]
+fn scan_snippet() {
+    let terms = terms("Meet LIBRARY");
+    let data = Data::Message("Let's meet at the library, then meet again".to_string());
+
+    let highlighted = snippet(&data, &terms)
+        .unwrap()
+        .into_iter()
+        .filter(|x| x.highlight)
+        .map(|x| x.text)
+        .collect::<Vec<_>>();
+
+    assert_eq!(highlighted, ["meet", "library", "meet"]);
+    assert!(snippet(&data, &["nothing".to_string()]).is_none());
+    assert!(snippet(&data, &["ibrary".to_string()]).is_none());
+    assert!(snippet(&data, &["lib".to_string()]).is_some());
+}
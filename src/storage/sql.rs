@@ -0,0 +1,885 @@
+/// Implements `StorageBackend` for a struct holding a diesel connection in `conn` and an optional
+/// `Cipher` in `cipher`. The queries are the same for every SQL database, only the `MIGRATIONS`
+/// constant in scope and the struct's `backup`, `vacuum`, `index`, `rebuild_index` and
+/// `search_index` methods differ.
+macro_rules! impl_sql_backend {
+    ($backend:ident) => {
+        impl $backend {
+            fn encrypt(&self, plaintext: Vec<u8>) -> $crate::error::Result<Vec<u8>> {
+                match &self.cipher {
+                    Some(cipher) => cipher.encrypt(&plaintext),
+                    None => Ok(plaintext),
+                }
+            }
+
+            fn decrypt(&self, ciphertext: Vec<u8>) -> $crate::error::Result<Vec<u8>> {
+                match &self.cipher {
+                    Some(cipher) => cipher.decrypt(&ciphertext),
+                    None => Ok(ciphertext),
+                }
+            }
+
+            /// Deletes the reactions of a conversation whose message is no longer stored
+            fn prune_reactions(&self, host: &[u8], peer: &[u8]) -> $crate::error::Result<()> {
+                use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+                use $crate::storage::schema::{messages, reactions};
+
+                let targets = reactions::table
+                    .filter(reactions::host_public_key.eq(host))
+                    .filter(reactions::peer_public_key.eq(peer))
+                    .select(reactions::target_id)
+                    .distinct()
+                    .load::<Vec<u8>>(&self.conn)?;
+
+                let kept = messages::table
+                    .filter(messages::host_public_key.eq(host))
+                    .filter(messages::peer_public_key.eq(peer))
+                    .filter(messages::message_id.eq_any(&targets))
+                    .select(messages::message_id)
+                    .load::<Option<Vec<u8>>>(&self.conn)?;
+
+                diesel::delete(
+                    reactions::table
+                        .filter(reactions::host_public_key.eq(host))
+                        .filter(reactions::peer_public_key.eq(peer))
+                        .filter(
+                            reactions::target_id.eq_any(
+                                targets
+                                    .into_iter()
+                                    .filter(|x| !kept.iter().flatten().any(|y| x == y))
+                                    .collect::<Vec<_>>(),
+                            ),
+                        ),
+                )
+                .execute(&self.conn)?;
+
+                Ok(())
+            }
+        }
+
+        impl $crate::storage::StorageBackend for $backend {
+            fn migrate(&mut self) -> $crate::error::Result<()> {
+                $crate::storage::migrations::run(&self.conn, MIGRATIONS, |version| {
+                    self.backup(version)
+                })
+            }
+
+            fn unlock(
+                &mut self,
+                passphrase: Option<&$crate::storage::cipher::Passphrase>,
+            ) -> $crate::error::Result<()> {
+                use diesel::{
+                    Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl,
+                };
+
+                use $crate::{
+                    error::BlackedoutError,
+                    storage::{
+                        cipher::{random_key, unwrap_key, wrap_key, Cipher},
+                        schema::{contacts, encryption, messages, outbox, reactions},
+                    },
+                };
+
+                let stored = encryption::table
+                    .select((encryption::salt, encryption::key))
+                    .first::<(Vec<u8>, Vec<u8>)>(&self.conn)
+                    .optional()?;
+
+                let passphrase = match (passphrase, &stored) {
+                    (Some(passphrase), _) => passphrase,
+                    (None, Some(_)) => return Err(BlackedoutError::StorageEncrypted),
+                    (None, None) => return self.rebuild_index(),
+                };
+
+                let key = self.conn.transaction::<_, BlackedoutError, _>(|| {
+                    let key = match stored {
+                        Some((salt, wrapped)) => unwrap_key(&passphrase.current, &salt, &wrapped)?,
+                        None => {
+                            // Encryption was just enabled so encrypt what is already stored
+                            let key = random_key();
+                            let cipher = Cipher::new(&key);
+
+                            for (id, data) in messages::table
+                                .select((messages::id, messages::data))
+                                .load::<(i64, Vec<u8>)>(&self.conn)?
+                            {
+                                diesel::update(messages::table.find(id))
+                                    .set(messages::data.eq(cipher.encrypt(&data)?))
+                                    .execute(&self.conn)?;
+                            }
+
+                            for (id, data) in outbox::table
+                                .select((outbox::id, outbox::data))
+                                .load::<(i64, Vec<u8>)>(&self.conn)?
+                            {
+                                diesel::update(outbox::table.find(id))
+                                    .set(outbox::data.eq(cipher.encrypt(&data)?))
+                                    .execute(&self.conn)?;
+                            }
+
+                            for (host, peer, target_id, direction, emojis) in reactions::table
+                                .load::<(Vec<u8>, Vec<u8>, Vec<u8>, i32, Vec<u8>)>(&self.conn)?
+                            {
+                                diesel::update(
+                                    reactions::table.find((host, peer, target_id, direction)),
+                                )
+                                .set(reactions::emojis.eq(cipher.encrypt(&emojis)?))
+                                .execute(&self.conn)?;
+                            }
+
+                            for (host, peer, metadata) in contacts::table
+                                .select((
+                                    contacts::host_public_key,
+                                    contacts::peer_public_key,
+                                    contacts::metadata,
+                                ))
+                                .load::<(Vec<u8>, Vec<u8>, Vec<u8>)>(&self.conn)?
+                            {
+                                diesel::update(contacts::table.find((host, peer)))
+                                    .set(contacts::metadata.eq(cipher.encrypt(&metadata)?))
+                                    .execute(&self.conn)?;
+                            }
+
+                            let (salt, wrapped) = wrap_key(&passphrase.current, &key)?;
+
+                            diesel::insert_into(encryption::table)
+                                .values((
+                                    encryption::id.eq(0),
+                                    encryption::salt.eq(salt),
+                                    encryption::key.eq(wrapped),
+                                ))
+                                .execute(&self.conn)?;
+
+                            key
+                        }
+                    };
+
+                    if let Some(new) = &passphrase.new {
+                        let (salt, wrapped) = wrap_key(new, &key)?;
+
+                        diesel::update(encryption::table)
+                            .set((encryption::salt.eq(salt), encryption::key.eq(wrapped)))
+                            .execute(&self.conn)?;
+                    }
+
+                    Ok(key)
+                })?;
+
+                self.cipher = Some(Cipher::new(&key));
+
+                self.rebuild_index()
+            }
+
+            fn insert(
+                &mut self,
+                message: &$crate::storage::model::NewMessage,
+            ) -> $crate::error::Result<()> {
+                use diesel::{dsl::exists, ExpressionMethods, QueryDsl, RunQueryDsl};
+
+                use $crate::storage::schema::messages;
+
+                let mut row = message.to_row()?;
+
+                if let Some(message_id) = &row.message_id {
+                    let stored = diesel::select(exists(
+                        messages::table
+                            .filter(messages::host_public_key.eq(&row.host_public_key))
+                            .filter(messages::peer_public_key.eq(&row.peer_public_key))
+                            .filter(messages::direction.eq(row.direction))
+                            .filter(messages::message_id.eq(message_id)),
+                    ))
+                    .get_result::<bool>(&self.conn)?;
+
+                    if stored {
+                        return Ok(());
+                    }
+                }
+
+                row.data = self.encrypt(row.data)?;
+
+                diesel::insert_into(messages::table)
+                    .values(&row)
+                    .execute(&self.conn)?;
+
+                self.index(message)
+            }
+
+            fn query(
+                &mut self,
+                query: &$crate::client::model::FetchHistory,
+            ) -> $crate::error::Result<Vec<$crate::storage::model::Message>> {
+                use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+                use $crate::{
+                    client::model::MAX_HISTORY_LIMIT,
+                    storage::{model::MessageRow, schema::messages},
+                };
+
+                let mut select = messages::table
+                    .filter(
+                        messages::host_public_key.eq(&query.pair.host_public_key.as_bytes()[..]),
+                    )
+                    .filter(
+                        messages::peer_public_key.eq(&query.pair.peer_public_key.as_bytes()[..]),
+                    )
+                    .into_boxed();
+
+                if let Some(before) = query.before {
+                    select = select.filter(messages::id.lt(before));
+                }
+
+                if let Some(after) = query.after {
+                    select = select.filter(messages::id.gt(after));
+                }
+
+                // Pages anchored at `after` are read forwards, everything else backwards from the
+                // newest
+                let select = match query.after {
+                    Some(_) => select.order(messages::id.asc()),
+                    None => select.order(messages::id.desc()),
+                };
+
+                let mut rows = select
+                    .limit(query.limit.clamp(1, MAX_HISTORY_LIMIT))
+                    .load::<MessageRow>(&self.conn)?;
+
+                if query.after.is_none() {
+                    rows.reverse();
+                }
+
+                rows.into_iter()
+                    .map(|mut row| {
+                        row.data = self.decrypt(row.data)?;
+                        row.try_into()
+                    })
+                    .collect()
+            }
+
+            fn message(
+                &mut self,
+                pair: &$crate::client::model::PeerHostPair,
+                message_id: &$crate::types::MessageId,
+                direction: $crate::storage::model::Direction,
+            ) -> $crate::error::Result<Option<$crate::storage::model::Message>> {
+                use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+
+                use $crate::storage::{model::MessageRow, schema::messages};
+
+                messages::table
+                    .filter(messages::host_public_key.eq(&pair.host_public_key.as_bytes()[..]))
+                    .filter(messages::peer_public_key.eq(&pair.peer_public_key.as_bytes()[..]))
+                    .filter(messages::direction.eq(direction as i32))
+                    .filter(messages::message_id.eq(&message_id.as_bytes()[..]))
+                    .first::<MessageRow>(&self.conn)
+                    .optional()?
+                    .map(|mut row| {
+                        row.data = self.decrypt(row.data)?;
+                        row.try_into()
+                    })
+                    .transpose()
+            }
+
+            fn delete_message(
+                &mut self,
+                pair: &$crate::client::model::PeerHostPair,
+                message_id: &$crate::types::MessageId,
+                direction: $crate::storage::model::Direction,
+            ) -> $crate::error::Result<usize> {
+                use diesel::{
+                    dsl::exists, BoolExpressionMethods, ExpressionMethods, QueryDsl, RunQueryDsl,
+                };
+
+                use $crate::storage::schema::{messages, reactions};
+
+                let host = &pair.host_public_key.as_bytes()[..];
+                let peer = &pair.peer_public_key.as_bytes()[..];
+                let message_id = &message_id.as_bytes()[..];
+
+                let deleted = diesel::delete(messages::table)
+                    .filter(messages::host_public_key.eq(host))
+                    .filter(messages::peer_public_key.eq(peer))
+                    .filter(messages::direction.eq(direction as i32))
+                    .filter(
+                        messages::message_id
+                            .eq(message_id)
+                            .or(messages::target_id.eq(message_id)),
+                    )
+                    .execute(&self.conn)?;
+
+                // Reactions don't say which side's message they are for, so they stay while the
+                // other side still has a message with this ID
+                let reused = diesel::select(exists(
+                    messages::table
+                        .filter(messages::host_public_key.eq(host))
+                        .filter(messages::peer_public_key.eq(peer))
+                        .filter(messages::direction.eq(direction.other() as i32))
+                        .filter(messages::message_id.eq(message_id)),
+                ))
+                .get_result::<bool>(&self.conn)?;
+
+                if !reused {
+                    diesel::delete(reactions::table)
+                        .filter(reactions::host_public_key.eq(host))
+                        .filter(reactions::peer_public_key.eq(peer))
+                        .filter(reactions::target_id.eq(message_id))
+                        .execute(&self.conn)?;
+                }
+
+                Ok(deleted)
+            }
+
+            fn react(
+                &mut self,
+                pair: &$crate::client::model::PeerHostPair,
+                target_id: &$crate::types::MessageId,
+                direction: $crate::storage::model::Direction,
+                emoji: &str,
+                remove: bool,
+            ) -> $crate::error::Result<()> {
+                use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+
+                use $crate::storage::{model::Emojis, schema::reactions};
+
+                let host = &pair.host_public_key.as_bytes()[..];
+                let peer = &pair.peer_public_key.as_bytes()[..];
+                let target_id = &target_id.as_bytes()[..];
+                let row = reactions::table.find((host, peer, target_id, direction as i32));
+
+                let mut emojis = match row
+                    .select(reactions::emojis)
+                    .first::<Vec<u8>>(&self.conn)
+                    .optional()?
+                {
+                    Some(n) => bson::from_slice::<Emojis>(&self.decrypt(n)?)?,
+                    None => Emojis::default(),
+                };
+
+                match remove {
+                    true => emojis.emojis.retain(|x| x != emoji),
+                    false if !emojis.emojis.iter().any(|x| x == emoji) => {
+                        emojis.emojis.push(emoji.to_string())
+                    }
+                    false => return Ok(()),
+                }
+
+                diesel::delete(row).execute(&self.conn)?;
+
+                if !emojis.emojis.is_empty() {
+                    diesel::insert_into(reactions::table)
+                        .values((
+                            reactions::host_public_key.eq(host),
+                            reactions::peer_public_key.eq(peer),
+                            reactions::target_id.eq(target_id),
+                            reactions::direction.eq(direction as i32),
+                            reactions::emojis.eq(self.encrypt(bson::to_vec(&emojis)?)?),
+                        ))
+                        .execute(&self.conn)?;
+                }
+
+                Ok(())
+            }
+
+            fn reactions(
+                &mut self,
+                pair: &$crate::client::model::PeerHostPair,
+                message_ids: &[$crate::types::MessageId],
+            ) -> $crate::error::Result<
+                std::collections::HashMap<
+                    $crate::types::MessageId,
+                    Vec<$crate::storage::model::Reaction>,
+                >,
+            > {
+                use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+                use $crate::{
+                    storage::{
+                        model::{Emojis, Reaction},
+                        schema::reactions,
+                    },
+                    types::MessageId,
+                };
+
+                let mut grouped = std::collections::HashMap::<MessageId, Vec<Reaction>>::new();
+
+                for (target_id, direction, emojis) in reactions::table
+                    .filter(reactions::host_public_key.eq(&pair.host_public_key.as_bytes()[..]))
+                    .filter(reactions::peer_public_key.eq(&pair.peer_public_key.as_bytes()[..]))
+                    .filter(
+                        reactions::target_id
+                            .eq_any(message_ids.iter().map(|x| x.as_bytes().to_vec())),
+                    )
+                    .select((
+                        reactions::target_id,
+                        reactions::direction,
+                        reactions::emojis,
+                    ))
+                    .order(reactions::direction.asc())
+                    .load::<(Vec<u8>, i32, Vec<u8>)>(&self.conn)?
+                {
+                    let direction = direction.try_into()?;
+
+                    grouped
+                        .entry(MessageId::from_bytes(&target_id)?)
+                        .or_default()
+                        .extend(
+                            bson::from_slice::<Emojis>(&self.decrypt(emojis)?)?
+                                .emojis
+                                .into_iter()
+                                .map(|emoji| Reaction { emoji, direction }),
+                        );
+                }
+
+                Ok(grouped)
+            }
+
+            fn delete(
+                &mut self,
+                delete: &$crate::client::model::DeleteHistory,
+            ) -> $crate::error::Result<usize> {
+                use diesel::{ExpressionMethods, RunQueryDsl};
+
+                use $crate::storage::schema::messages;
+
+                let mut select = diesel::delete(messages::table)
+                    .filter(
+                        messages::host_public_key.eq(&delete.pair.host_public_key.as_bytes()[..]),
+                    )
+                    .filter(
+                        messages::peer_public_key.eq(&delete.pair.peer_public_key.as_bytes()[..]),
+                    )
+                    .into_boxed();
+
+                if let Some(until) = delete.until {
+                    select = select.filter(messages::timestamp.le(until));
+                }
+
+                let deleted = select.execute(&self.conn)?;
+                self.prune_reactions(
+                    &delete.pair.host_public_key.as_bytes()[..],
+                    &delete.pair.peer_public_key.as_bytes()[..],
+                )?;
+
+                Ok(deleted)
+            }
+
+            fn conversations(
+                &mut self,
+            ) -> $crate::error::Result<Vec<$crate::client::model::PeerHostPair>> {
+                use diesel::{QueryDsl, RunQueryDsl};
+
+                use $crate::{
+                    client::model::PeerHostPair, storage::schema::messages, types::PublicKey,
+                };
+
+                messages::table
+                    .select((messages::host_public_key, messages::peer_public_key))
+                    .distinct()
+                    .load::<(Vec<u8>, Vec<u8>)>(&self.conn)?
+                    .into_iter()
+                    .map(|(host, peer)| {
+                        Ok(PeerHostPair {
+                            peer_public_key: PublicKey::from_bytes(&peer)?,
+                            host_public_key: PublicKey::from_bytes(&host)?,
+                        })
+                    })
+                    .collect()
+            }
+
+            fn truncate(
+                &mut self,
+                pair: &$crate::client::model::PeerHostPair,
+                keep: usize,
+            ) -> $crate::error::Result<usize> {
+                use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+
+                use $crate::storage::schema::messages;
+
+                let host = &pair.host_public_key.as_bytes()[..];
+                let peer = &pair.peer_public_key.as_bytes()[..];
+
+                // The newest message that is no longer kept
+                let newest = messages::table
+                    .filter(messages::host_public_key.eq(host))
+                    .filter(messages::peer_public_key.eq(peer))
+                    .select(messages::id)
+                    .order(messages::id.desc())
+                    .offset(keep as i64)
+                    .first::<i64>(&self.conn)
+                    .optional()?;
+
+                let newest = match newest {
+                    Some(n) => n,
+                    None => return Ok(0),
+                };
+
+                let deleted = diesel::delete(
+                    messages::table
+                        .filter(messages::host_public_key.eq(host))
+                        .filter(messages::peer_public_key.eq(peer))
+                        .filter(messages::id.le(newest)),
+                )
+                .execute(&self.conn)?;
+                self.prune_reactions(host, peer)?;
+
+                Ok(deleted)
+            }
+
+            fn compact(&mut self) -> $crate::error::Result<()> {
+                self.vacuum()
+            }
+
+            fn search(
+                &mut self,
+                search: &$crate::client::model::Search,
+            ) -> $crate::error::Result<Vec<$crate::storage::model::SearchResult>> {
+                use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+                use $crate::{
+                    client::model::MAX_HISTORY_LIMIT,
+                    storage::{
+                        model::{Message, MessageRow, SearchResult},
+                        schema::messages,
+                        search::{snippet, terms},
+                    },
+                };
+
+                let terms = terms(&search.query);
+
+                if terms.is_empty() {
+                    return Ok(Vec::new());
+                }
+
+                if let Some(results) = self.search_index(search, &terms)? {
+                    return Ok(results);
+                }
+
+                // Without an index every message in range is decrypted and scanned
+                let mut select = messages::table.order(messages::id.desc()).into_boxed();
+
+                if let Some(host) = &search.host_public_key {
+                    select = select.filter(messages::host_public_key.eq(&host.as_bytes()[..]));
+                }
+
+                if let Some(peer) = &search.peer_public_key {
+                    select = select.filter(messages::peer_public_key.eq(&peer.as_bytes()[..]));
+                }
+
+                if let Some(since) = search.since {
+                    select = select.filter(messages::timestamp.ge(since));
+                }
+
+                if let Some(until) = search.until {
+                    select = select.filter(messages::timestamp.le(until));
+                }
+
+                let limit = search.limit.clamp(1, MAX_HISTORY_LIMIT) as usize;
+                let mut results = Vec::new();
+
+                for mut row in select.load::<MessageRow>(&self.conn)? {
+                    row.data = self.decrypt(row.data)?;
+                    let message: Message = row.try_into()?;
+
+                    if let Some(snippet) = snippet(&message.data, &terms) {
+                        results.push(SearchResult { message, snippet });
+                    }
+
+                    if results.len() == limit {
+                        break;
+                    }
+                }
+
+                Ok(results)
+            }
+
+            fn add_contact(
+                &mut self,
+                contact: &$crate::client::model::AddContact,
+                timestamp: i64,
+            ) -> $crate::error::Result<()> {
+                use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+                use $crate::storage::schema::contacts;
+
+                let host = &contact.pair.host_public_key.as_bytes()[..];
+                let peer = &contact.pair.peer_public_key.as_bytes()[..];
+                let metadata = self.encrypt(bson::to_vec(&contact.metadata)?)?;
+
+                if diesel::update(contacts::table.find((host, peer)))
+                    .set(contacts::metadata.eq(&metadata))
+                    .execute(&self.conn)?
+                    == 0
+                {
+                    diesel::insert_into(contacts::table)
+                        .values((
+                            contacts::host_public_key.eq(host),
+                            contacts::peer_public_key.eq(peer),
+                            contacts::metadata.eq(&metadata),
+                            contacts::first_seen.eq(timestamp),
+                        ))
+                        .execute(&self.conn)?;
+                }
+
+                Ok(())
+            }
+
+            fn rename_contact(
+                &mut self,
+                pair: &$crate::client::model::PeerHostPair,
+                nickname: &str,
+            ) -> $crate::error::Result<()> {
+                use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+
+                use $crate::{
+                    error::BlackedoutError,
+                    storage::{model::ContactMetadata, schema::contacts},
+                };
+
+                let contact = contacts::table.find((
+                    &pair.host_public_key.as_bytes()[..],
+                    &pair.peer_public_key.as_bytes()[..],
+                ));
+
+                let mut metadata: ContactMetadata = bson::from_slice(
+                    &self.decrypt(
+                        contact
+                            .select(contacts::metadata)
+                            .first::<Vec<u8>>(&self.conn)
+                            .optional()?
+                            .ok_or(BlackedoutError::ContactDoesNotExist)?,
+                    )?,
+                )?;
+
+                metadata.nickname = nickname.to_string();
+
+                diesel::update(contact)
+                    .set(contacts::metadata.eq(self.encrypt(bson::to_vec(&metadata)?)?))
+                    .execute(&self.conn)?;
+
+                Ok(())
+            }
+
+            fn remove_contact(
+                &mut self,
+                pair: &$crate::client::model::PeerHostPair,
+            ) -> $crate::error::Result<usize> {
+                use diesel::{QueryDsl, RunQueryDsl};
+
+                use $crate::storage::schema::contacts;
+
+                diesel::delete(contacts::table.find((
+                    &pair.host_public_key.as_bytes()[..],
+                    &pair.peer_public_key.as_bytes()[..],
+                )))
+                .execute(&self.conn)
+                .map_err(Into::into)
+            }
+
+            fn touch_contact(
+                &mut self,
+                pair: &$crate::client::model::PeerHostPair,
+                timestamp: i64,
+            ) -> $crate::error::Result<()> {
+                use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+                use $crate::storage::{model::ContactMetadata, schema::contacts};
+
+                let host = &pair.host_public_key.as_bytes()[..];
+                let peer = &pair.peer_public_key.as_bytes()[..];
+
+                if diesel::update(contacts::table.find((host, peer)))
+                    .set(contacts::last_seen.eq(timestamp))
+                    .execute(&self.conn)?
+                    == 0
+                {
+                    diesel::insert_into(contacts::table)
+                        .values((
+                            contacts::host_public_key.eq(host),
+                            contacts::peer_public_key.eq(peer),
+                            contacts::metadata
+                                .eq(self.encrypt(bson::to_vec(&ContactMetadata::default())?)?),
+                            contacts::first_seen.eq(timestamp),
+                            contacts::last_seen.eq(timestamp),
+                        ))
+                        .execute(&self.conn)?;
+                }
+
+                Ok(())
+            }
+
+            fn contacts(&mut self) -> $crate::error::Result<Vec<$crate::storage::model::Contact>> {
+                use diesel::{QueryDsl, RunQueryDsl};
+
+                use $crate::storage::{model::ContactRow, schema::contacts};
+
+                contacts::table
+                    .order((contacts::host_public_key, contacts::first_seen))
+                    .load::<ContactRow>(&self.conn)?
+                    .into_iter()
+                    .map(|mut row| {
+                        row.metadata = self.decrypt(row.metadata)?;
+                        row.try_into()
+                    })
+                    .collect()
+            }
+
+            fn enqueue(
+                &mut self,
+                entry: &$crate::storage::model::OutboxEntry,
+            ) -> $crate::error::Result<()> {
+                use diesel::{ExpressionMethods, RunQueryDsl};
+
+                use $crate::storage::schema::outbox;
+
+                diesel::insert_into(outbox::table)
+                    .values((
+                        outbox::host_public_key.eq(&entry.pair.host_public_key.as_bytes()[..]),
+                        outbox::peer_public_key.eq(&entry.pair.peer_public_key.as_bytes()[..]),
+                        outbox::token.eq(&entry.token[..]),
+                        outbox::message_id.eq(&entry.envelope.message_id.as_bytes()[..]),
+                        outbox::sent_at.eq(entry.envelope.sent_at),
+                        outbox::data.eq(self.encrypt(bson::to_vec(&entry.envelope.data)?)?),
+                    ))
+                    .execute(&self.conn)?;
+
+                Ok(())
+            }
+
+            fn outbox(
+                &mut self,
+                pair: Option<&$crate::client::model::PeerHostPair>,
+            ) -> $crate::error::Result<Vec<$crate::storage::model::OutboxEntry>> {
+                use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+                use $crate::storage::{model::OutboxRow, schema::outbox};
+
+                let mut select = outbox::table
+                    .select((
+                        outbox::host_public_key,
+                        outbox::peer_public_key,
+                        outbox::token,
+                        outbox::message_id,
+                        outbox::sent_at,
+                        outbox::data,
+                    ))
+                    .order(outbox::id.asc())
+                    .into_boxed();
+
+                if let Some(pair) = pair {
+                    select = select
+                        .filter(outbox::host_public_key.eq(&pair.host_public_key.as_bytes()[..]))
+                        .filter(outbox::peer_public_key.eq(&pair.peer_public_key.as_bytes()[..]));
+                }
+
+                select
+                    .load::<OutboxRow>(&self.conn)?
+                    .into_iter()
+                    .map(|mut row| {
+                        row.data = self.decrypt(row.data)?;
+                        row.try_into()
+                    })
+                    .collect()
+            }
+
+            fn outbox_pairs(
+                &mut self,
+            ) -> $crate::error::Result<Vec<$crate::client::model::PeerHostPair>> {
+                use diesel::{QueryDsl, RunQueryDsl};
+
+                use $crate::{
+                    client::model::PeerHostPair, storage::schema::outbox, types::PublicKey,
+                };
+
+                outbox::table
+                    .select((outbox::host_public_key, outbox::peer_public_key))
+                    .distinct()
+                    .load::<(Vec<u8>, Vec<u8>)>(&self.conn)?
+                    .into_iter()
+                    .map(|(host, peer)| {
+                        Ok(PeerHostPair {
+                            peer_public_key: PublicKey::from_bytes(&peer)?,
+                            host_public_key: PublicKey::from_bytes(&host)?,
+                        })
+                    })
+                    .collect()
+            }
+
+            fn dequeue(
+                &mut self,
+                message_id: &$crate::types::MessageId,
+            ) -> $crate::error::Result<usize> {
+                use diesel::{ExpressionMethods, RunQueryDsl};
+
+                use $crate::storage::schema::outbox;
+
+                diesel::delete(outbox::table)
+                    .filter(outbox::message_id.eq(&message_id.as_bytes()[..]))
+                    .execute(&self.conn)
+                    .map_err(Into::into)
+            }
+
+            fn identity_key(
+                &mut self,
+                pair: &$crate::client::model::PeerHostPair,
+            ) -> $crate::error::Result<Option<Vec<u8>>> {
+                use diesel::{OptionalExtension, QueryDsl, RunQueryDsl};
+
+                use $crate::storage::schema::identity_keys;
+
+                identity_keys::table
+                    .find((
+                        &pair.host_public_key.as_bytes()[..],
+                        &pair.peer_public_key.as_bytes()[..],
+                    ))
+                    .select(identity_keys::kyber_public_key)
+                    .first::<Vec<u8>>(&self.conn)
+                    .optional()
+                    .map_err(Into::into)
+            }
+
+            fn pin_identity_key(
+                &mut self,
+                pair: &$crate::client::model::PeerHostPair,
+                key: &[u8],
+                timestamp: i64,
+            ) -> $crate::error::Result<()> {
+                use diesel::{ExpressionMethods, RunQueryDsl};
+
+                use $crate::storage::schema::identity_keys;
+
+                if self.identity_key(pair)?.is_some() {
+                    return Ok(());
+                }
+
+                diesel::insert_into(identity_keys::table)
+                    .values((
+                        identity_keys::host_public_key.eq(&pair.host_public_key.as_bytes()[..]),
+                        identity_keys::peer_public_key.eq(&pair.peer_public_key.as_bytes()[..]),
+                        identity_keys::kyber_public_key.eq(key),
+                        identity_keys::pinned_at.eq(timestamp),
+                    ))
+                    .execute(&self.conn)?;
+
+                Ok(())
+            }
+
+            fn unpin_identity_key(
+                &mut self,
+                pair: &$crate::client::model::PeerHostPair,
+            ) -> $crate::error::Result<usize> {
+                use diesel::{QueryDsl, RunQueryDsl};
+
+                use $crate::storage::schema::identity_keys;
+
+                diesel::delete(identity_keys::table.find((
+                    &pair.host_public_key.as_bytes()[..],
+                    &pair.peer_public_key.as_bytes()[..],
+                )))
+                .execute(&self.conn)
+                .map_err(Into::into)
+            }
+        }
+    };
+}
+
+pub(super) use impl_sql_backend;
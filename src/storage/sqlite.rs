@@ -0,0 +1,486 @@
+use std::path::{Path, PathBuf};
+
+use diesel::{
+    connection::SimpleConnection,
+    sql_types::{BigInt, Binary, Nullable, Text},
+    sqlite::SqliteConnection,
+    Connection, ExpressionMethods, QueryDsl, RunQueryDsl,
+};
+
+use crate::{
+    client::model::{Search, MAX_HISTORY_LIMIT},
+    connections::model::Data,
+    error::Result,
+};
+
+use super::{
+    cipher::Cipher,
+    migrations::{embed_migration, EmbeddedMigration},
+    model::{MessageRow, NewMessage, SearchResult, SearchRow},
+    schema::{messages, messages_fts},
+    search::{fts_query, parse_snippet, HIGHLIGHT_END, HIGHLIGHT_START},
+    sql::impl_sql_backend,
+};
+
+const MIGRATIONS: &[EmbeddedMigration] = &[
+    embed_migration!("sqlite", "20261018000000", "create_messages"),
+    embed_migration!("sqlite", "20261018000001", "create_contacts"),
+    embed_migration!("sqlite", "20261018000002", "create_messages_fts"),
+    embed_migration!("sqlite", "20261018000003", "add_message_envelopes"),
+    embed_migration!("sqlite", "20261018000004", "create_outbox"),
+    embed_migration!("sqlite", "20261018000005", "add_message_targets"),
+    embed_migration!("sqlite", "20261018000006", "create_reactions"),
+    embed_migration!("sqlite", "20261018000007", "create_identity_keys"),
+    embed_migration!("sqlite", "20261018000008", "add_messages_fts_trigger"),
+];
+
+pub struct Sqlite {
+    conn: SqliteConnection,
+    cipher: Option<Cipher>,
+    path: PathBuf,
+}
+
+impl Sqlite {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = SqliteConnection::establish(&path.to_string_lossy())?;
+
+        // Overwrite deleted content with zeros instead of leaving it in free pages
+        conn.batch_execute("PRAGMA secure_delete = ON")?;
+
+        Ok(Sqlite {
+            conn,
+            cipher: None,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Copies the database next to itself as `<path>.<version>.bak`
+    fn backup(&self, version: &str) -> Result<()> {
+        let mut backup = self.path.clone().into_os_string();
+        backup.push(format!(".{}.bak", version));
+
+        diesel::sql_query("VACUUM INTO ?")
+            .bind::<Text, _>(backup.to_string_lossy())
+            .execute(&self.conn)?;
+
+        Ok(())
+    }
+
+    /// Merges the full-text index so it drops deleted bodies, then rebuilds the file without
+    /// free pages
+    fn vacuum(&self) -> Result<()> {
+        self.conn
+            .batch_execute("INSERT INTO messages_fts (messages_fts) VALUES ('optimize'); VACUUM;")
+            .map_err(Into::into)
+    }
+
+    /// Adds the message that was just inserted to the full-text index. Encrypted databases are
+    /// not indexed since the index would keep the plaintext.
+    fn index(&self, message: &NewMessage) -> Result<()> {
+        if self.cipher.is_some() {
+            return Ok(());
+        }
+
+        diesel::sql_query("INSERT INTO messages_fts (rowid, body) VALUES (last_insert_rowid(), ?)")
+            .bind::<Text, _>(message.data.text().unwrap_or_default())
+            .execute(&self.conn)?;
+
+        Ok(())
+    }
+
+    /// Drops index entries of messages deleted before the trigger doing so existed and indexes
+    /// messages stored before the index existed. Empties the index of encrypted databases.
+    fn rebuild_index(&self) -> Result<()> {
+        if self.cipher.is_some() {
+            return self.clear_index();
+        }
+
+        diesel::delete(
+            messages_fts::table
+                .filter(messages_fts::rowid.ne_all(messages::table.select(messages::id))),
+        )
+        .execute(&self.conn)?;
+
+        for row in messages::table
+            .filter(messages::id.ne_all(messages_fts::table.select(messages_fts::rowid)))
+            .load::<MessageRow>(&self.conn)?
+        {
+            let data = bson::from_slice::<Data>(&row.data)?;
+
+            diesel::insert_into(messages_fts::table)
+                .values((
+                    messages_fts::rowid.eq(row.id),
+                    messages_fts::body.eq(data.text().unwrap_or_default()),
+                ))
+                .execute(&self.conn)?;
+        }
+
+        Ok(())
+    }
+
+    /// Deleting rows only marks them deleted in the index segments, which keep the words. The
+    /// index is recreated instead and the freed pages vacuumed away.
+    fn clear_index(&self) -> Result<()> {
+        self.conn.batch_execute(
+            "DROP TABLE messages_fts; CREATE VIRTUAL TABLE messages_fts USING fts5(body);",
+        )?;
+
+        let free = diesel::select(diesel::dsl::sql::<BigInt>(
+            "(SELECT freelist_count FROM pragma_freelist_count())",
+        ))
+        .get_result::<i64>(&self.conn)?;
+
+        match free {
+            0 => Ok(()),
+            _ => self.vacuum(),
+        }
+    }
+
+    fn search_index(&self, search: &Search, terms: &[String]) -> Result<Option<Vec<SearchResult>>> {
+        if self.cipher.is_some() {
+            return Ok(None);
+        }
+
+        let host = search.host_public_key.map(|x| x.as_bytes().to_vec());
+        let peer = search.peer_public_key.map(|x| x.as_bytes().to_vec());
+
+        diesel::sql_query(
+            "
+SELECT m.*, snippet(messages_fts, 0, ?, ?, '…', 16) AS snippet
+FROM messages_fts JOIN messages m ON m.id = messages_fts.rowid
+WHERE messages_fts MATCH ?
+    AND (? IS NULL OR m.host_public_key = ?)
+    AND (? IS NULL OR m.peer_public_key = ?)
+    AND (? IS NULL OR m.timestamp >= ?)
+    AND (? IS NULL OR m.timestamp <= ?)
+ORDER BY m.id DESC
+LIMIT ?
+",
+        )
+        .bind::<Text, _>(HIGHLIGHT_START.to_string())
+        .bind::<Text, _>(HIGHLIGHT_END.to_string())
+        .bind::<Text, _>(fts_query(terms))
+        .bind::<Nullable<Binary>, _>(host.clone())
+        .bind::<Nullable<Binary>, _>(host)
+        .bind::<Nullable<Binary>, _>(peer.clone())
+        .bind::<Nullable<Binary>, _>(peer)
+        .bind::<Nullable<BigInt>, _>(search.since)
+        .bind::<Nullable<BigInt>, _>(search.since)
+        .bind::<Nullable<BigInt>, _>(search.until)
+        .bind::<Nullable<BigInt>, _>(search.until)
+        .bind::<BigInt, _>(search.limit.clamp(1, MAX_HISTORY_LIMIT))
+        .load::<SearchRow>(&self.conn)?
+        .into_iter()
+        .map(|row| {
+            Ok(SearchResult {
+                message: MessageRow {
+                    id: row.id,
+                    host_public_key: row.host_public_key,
+                    peer_public_key: row.peer_public_key,
+                    direction: row.direction,
+                    timestamp: row.timestamp,
+                    data: row.data,
+                    message_id: row.message_id,
+                    sent_at: row.sent_at,
+                    _target_id: row.target_id,
+                }
+                .try_into()?,
+                snippet: parse_snippet(&row.snippet),
+            })
+        })
+        .collect::<Result<_>>()
+        .map(Some)
+    }
+}
+
+impl_sql_backend!(Sqlite);
+
+#[test]
+fn insert_message() {
+    use diesel::{dsl::count_star, QueryDsl, RunQueryDsl};
+
+    use crate::{
+        client::model::PeerHostPair,
+        connections::model::Data,
+        storage::{
+            model::{Direction, NewMessage},
+            schema::messages,
+            StorageBackend,
+        },
+        types::PublicKey,
+    };
+
+    let key = PublicKey::from_onion_address(
+        "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion",
+    )
+    .unwrap();
+
+    let mut sqlite = Sqlite::open(Path::new(":memory:")).unwrap();
+    sqlite.migrate().unwrap();
+    sqlite
+        .insert(&NewMessage {
+            pair: PeerHostPair {
+                peer_public_key: key,
+                host_public_key: key,
+            },
+            direction: Direction::Outgoing,
+            timestamp: 0,
+            message_id: None,
+            sent_at: None,
+            data: Data::Message("hello".to_string()),
+        })
+        .unwrap();
+
+    assert_eq!(
+        messages::table
+            .select(count_star())
+            .first::<i64>(&sqlite.conn)
+            .unwrap(),
+        1
+    );
+}
+
+#[test]
+fn sqlite_backend() {
+    let mut sqlite = Sqlite::open(Path::new(":memory:")).unwrap();
+    super::test_backend(&mut sqlite);
+}
+
+#[test]
+fn encrypted_storage() {
+    use diesel::{QueryDsl, RunQueryDsl};
+
+    use crate::{
+        client::model::{FetchHistory, PeerHostPair},
+        connections::model::Data,
+        error::BlackedoutError,
+        storage::{
+            cipher::Passphrase,
+            model::{Direction, NewMessage},
+            schema::messages,
+            StorageBackend,
+        },
+        types::PublicKey,
+    };
+
+    let path = std::env::temp_dir().join(format!("blackedout-{}.db", rand::random::<u64>()));
+    let key = PublicKey::from_onion_address(
+        "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion",
+    )
+    .unwrap();
+    let pair = PeerHostPair {
+        peer_public_key: key,
+        host_public_key: key,
+    };
+
+    let open = |current: &str, new: Option<&str>| {
+        let mut sqlite = Sqlite::open(&path).unwrap();
+        sqlite.migrate().unwrap();
+        sqlite
+            .unlock(Some(&Passphrase {
+                current: current.to_string(),
+                new: new.map(ToString::to_string),
+            }))
+            .map(|_| sqlite)
+    };
+
+    // Plaintext written before encryption is enabled gets encrypted too
+    let mut sqlite = Sqlite::open(&path).unwrap();
+    sqlite.migrate().unwrap();
+    sqlite.unlock(None).unwrap();
+
+    for body in ["plaintext", "ciphertext"] {
+        sqlite
+            .insert(&NewMessage {
+                pair: pair.clone(),
+                direction: Direction::Incoming,
+                timestamp: 0,
+                message_id: None,
+                sent_at: None,
+                data: Data::Message(body.to_string()),
+            })
+            .unwrap();
+
+        sqlite = open("old", None).unwrap();
+    }
+
+    assert!(messages::table
+        .select(messages::data)
+        .load::<Vec<u8>>(&sqlite.conn)
+        .unwrap()
+        .iter()
+        .all(|x| bson::from_slice::<Data>(x).is_err()));
+
+    // Neither the message nor its index entry leave the word in the file
+    assert!(!std::fs::read(&path)
+        .unwrap()
+        .windows(9)
+        .any(|x| x == b"plaintext"));
+
+    assert!(matches!(
+        Sqlite::open(&path).unwrap().unlock(None),
+        Err(BlackedoutError::StorageEncrypted)
+    ));
+    assert!(matches!(
+        open("wrong", None),
+        Err(BlackedoutError::BadPassphrase)
+    ));
+
+    open("old", Some("new")).unwrap();
+    assert!(open("old", None).is_err());
+
+    let messages = open("new", None)
+        .unwrap()
+        .query(&FetchHistory {
+            pair,
+            before: None,
+            after: None,
+            limit: 10,
+        })
+        .unwrap();
+
+    assert_eq!(messages.len(), 2);
+    std::fs::remove_file(path).ok();
+}
+
+#[test]
+fn backup_database() {
+    use crate::storage::StorageBackend;
+
+    let path = std::env::temp_dir().join(format!("blackedout-{}.db", rand::random::<u64>()));
+    let mut sqlite = Sqlite::open(&path).unwrap();
+    sqlite.migrate().unwrap();
+    sqlite.backup("20261018000000").unwrap();
+
+    let mut backup = path.clone().into_os_string();
+    backup.push(".20261018000000.bak");
+
+    Sqlite::open(Path::new(&backup)).unwrap().migrate().unwrap();
+
+    std::fs::remove_file(path).ok();
+    std::fs::remove_file(backup).ok();
+}
+
+#[test]
+fn full_text_search() {
+    use diesel::dsl::count_star;
+
+    use crate::{
+        client::model::{DeleteHistory, PeerHostPair},
+        storage::{model::Direction, StorageBackend},
+        types::PublicKey,
+    };
+
+    let key = PublicKey::from_onion_address(
+        "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion",
+    )
+    .unwrap();
+    let pair = PeerHostPair {
+        peer_public_key: key,
+        host_public_key: key,
+    };
+
+    let mut sqlite = Sqlite::open(Path::new(":memory:")).unwrap();
+    sqlite.migrate().unwrap();
+    sqlite
+        .insert(&NewMessage {
+            pair: pair.clone(),
+            direction: Direction::Incoming,
+            timestamp: 0,
+            message_id: None,
+            sent_at: None,
+            data: Data::Message("The keys are at the Library on 5th street".to_string()),
+        })
+        .unwrap();
+
+    let results = sqlite
+        .search(&Search {
+            query: "librar STREET".to_string(),
+            host_public_key: None,
+            peer_public_key: Some(key),
+            since: None,
+            until: None,
+            limit: 10,
+        })
+        .unwrap();
+
+    assert_eq!(results.len(), 1);
+    // Terms match the start of words only, the same as the scan without an index
+    assert!(sqlite
+        .search(&Search {
+            query: "ibrary".to_string(),
+            host_public_key: None,
+            peer_public_key: None,
+            since: None,
+            until: None,
+            limit: 10,
+        })
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        results[0]
+            .snippet
+            .iter()
+            .filter(|x| x.highlight)
+            .map(|x| x.text.as_str())
+            .collect::<Vec<_>>(),
+        ["Library", "street"]
+    );
+
+    sqlite.delete(&DeleteHistory { pair, until: None }).unwrap();
+
+    assert_eq!(
+        messages_fts::table
+            .select(count_star())
+            .first::<i64>(&sqlite.conn)
+            .unwrap(),
+        0
+    );
+}
+
+#[test]
+fn secure_delete() {
+    use crate::{
+        client::model::{DeleteHistory, PeerHostPair},
+        storage::{model::Direction, StorageBackend},
+        types::PublicKey,
+    };
+
+    let path = std::env::temp_dir().join(format!("blackedout-{}.db", rand::random::<u64>()));
+    let key = PublicKey::from_onion_address(
+        "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion",
+    )
+    .unwrap();
+    let pair = PeerHostPair {
+        peer_public_key: key,
+        host_public_key: key,
+    };
+
+    let mut sqlite = Sqlite::open(&path).unwrap();
+    sqlite.migrate().unwrap();
+    sqlite
+        .insert(&NewMessage {
+            pair: pair.clone(),
+            direction: Direction::Incoming,
+            timestamp: 0,
+            message_id: None,
+            sent_at: None,
+            data: Data::Message("burnafterreading".to_string()),
+        })
+        .unwrap();
+
+    let contains = || {
+        std::fs::read(&path)
+            .unwrap()
+            .windows(16)
+            .any(|x| x == b"burnafterreading")
+    };
+
+    assert!(contains());
+
+    sqlite.delete(&DeleteHistory { pair, until: None }).unwrap();
+    sqlite.compact().unwrap();
+
+    assert!(!contains());
+    std::fs::remove_file(path).ok();
+}
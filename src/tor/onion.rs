@@ -1,6 +1,14 @@
-use std::{collections::HashMap, fs, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions, Permissions},
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
 
 use ed25519_dalek::{ExpandedSecretKey, PublicKey as Ed25519PubKey};
+use pqcrypto_kyber::kyber102490s;
+use pqcrypto_traits::kem::{PublicKey as _, SecretKey as _};
 
 use crate::{
     config::Config,
@@ -12,6 +20,20 @@ pub struct Onion {
     pub name: String,
     pub public_key: PublicKey,
     pub secret_key: ExpandedSecretKey,
+    /// Post-quantum identity key that peers pin on first contact
+    pub kyber_public_key: Vec<u8>,
+    pub kyber_secret_key: Vec<u8>,
+}
+
+/// Reads the public key of an address from the hostname Tor wrote for it
+pub fn read_public_key(name: &str) -> Result<PublicKey> {
+    PublicKey::from_onion_address(&fs::read_to_string(
+        PathBuf::new()
+            .join("data")
+            .join("incoming")
+            .join(name)
+            .join("hostname"),
+    )?)
 }
 
 pub fn get_onion_data(config: &Config) -> Result<HashMap<PublicKey, Onion>> {
@@ -48,15 +70,49 @@ pub fn get_onion_data(config: &Config) -> Result<HashMap<PublicKey, Onion>> {
                         .then(|| ())
                         .ok_or(BlackedoutError::BadHostname)?;
 
+                    let (kyber_public_key, kyber_secret_key) = read_kyber_keys(&root)?;
+
                     Ok((
                         public_key,
                         Onion {
                             name: addr.name.clone(),
                             public_key,
                             secret_key,
+                            kyber_public_key,
+                            kyber_secret_key,
                         },
                     ))
                 })
         })
         .collect::<Result<HashMap<_, _>>>()
 }
+
+/// Reads the Kyber keypair kept next to the onion keys, generating it on first start
+fn read_kyber_keys(root: &Path) -> Result<(Vec<u8>, Vec<u8>)> {
+    let public_path = root.join("kyber_public_key");
+    let secret_path = root.join("kyber_secret_key");
+
+    if !secret_path.exists() {
+        let (pk, sk) = kyber102490s::keypair();
+
+        fs::write(&public_path, pk.as_bytes())?;
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&secret_path)?
+            .write_all(sk.as_bytes())?;
+    }
+
+    // Earlier versions created it readable by everyone the umask allowed
+    fs::set_permissions(&secret_path, Permissions::from_mode(0o600))?;
+
+    let public_key = fs::read(public_path)?;
+    let secret_key = fs::read(secret_path)?;
+
+    // Only checks the lengths
+    kyber102490s::PublicKey::from_bytes(&public_key)?;
+    kyber102490s::SecretKey::from_bytes(&secret_key)?;
+
+    Ok((public_key, secret_key))
+}
@@ -4,6 +4,7 @@ use serde::{
     de::{self, Visitor},
     Deserialize, Deserializer, Serialize,
 };
+use serde_with::{base64::Base64, serde_as};
 use sha3::{Digest, Sha3_256};
 
 use crate::error::{BlackedoutError, Result};
@@ -73,6 +74,28 @@ impl PublicKey {
     }
 }
 
+/// Random ID the sender gives a message, unique across every conversation
+#[serde_as]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct MessageId(#[serde_as(as = "Base64")] [u8; 16]);
+
+impl MessageId {
+    pub fn random() -> Self {
+        MessageId(rand::random())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        bytes
+            .try_into()
+            .map(MessageId)
+            .map_err(|_| BlackedoutError::CorruptedRecord)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
 impl<'de> Deserialize<'de> for PublicKey {
     fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
     where